@@ -258,6 +258,12 @@ BEGIN
   RETURN '''' | REPLACE( s, '''', '''''' ) | ''''
 END
 GO
+CREATE FN [sys].[TableId]( schema string, name string ) RETURNS int AS
+BEGIN
+  DECLARE sid int SET sid = Id FROM sys.Schema WHERE Name = schema
+  SET result = Id FROM sys.Table WHERE Schema = sid AND Name = name
+END
+GO
 CREATE FN [sys].[TableName]( table int ) RETURNS string AS
 BEGIN
   DECLARE schema int, name string
@@ -688,6 +694,17 @@ CREATE TABLE [web].[File]([Path] string,[ContentType] string,[ContentLength] int
 GO
 CREATE INDEX [ByPath] ON [web].[File]([Path])
 GO
+CREATE FN [web].[Bearer]() RETURNS string AS
+BEGIN
+  -- Bearer token from Authorization header ( not logged, so blank when a replica applies the transaction ).
+  RETURN BEARER()
+END
+GO
+CREATE FN [web].[ClientIp]() RETURNS string AS
+BEGIN
+  RETURN ARG( 3, 'ip' )
+END
+GO
 CREATE FN [web].[Cookie]( name string ) RETURNS string AS
 BEGIN
   RETURN ARG( 3, name )
@@ -1336,8 +1353,10 @@ BEGIN
   DECLARE ex string
   IF web.Form( '$submit' ) != '' 
   BEGIN
+    DECLARE ae int SET ae = audit.Start( cu, t, 0 )
     EXECUTE( browse.InsertSql( t, c, p ) ) 
     SET ex = EXCEPTION()
+    IF ex = '' EXEC audit.Finish( ae, LASTID() )
     IF ex = '' 
     BEGIN
       -- DECLARE ba string SET ba = browse.backargs()
@@ -1366,14 +1385,15 @@ BEGIN
   DECLARE ex string
   IF web.Form( '$submit' ) != '' 
   BEGIN
-    DECLARE lastid int
-    SET lastid = LASTID()
+    DECLARE ae int SET ae = audit.Start( cu, t, 0 )
     EXECUTE( browse.InsertSql( t, 0, 0 ) ) 
     SET ex = EXCEPTION()
     IF ex = '' 
     BEGIN
+      DECLARE lastid int SET lastid = LASTID()
+      EXEC audit.Finish( ae, lastid )
       DECLARE ba string SET ba = browse.backargs()
-      EXEC web.Redirect( 'ShowRow?' | browse.tablearg(t) | '&k=' | LASTID() | ba )
+      EXEC web.Redirect( 'ShowRow?' | browse.tablearg(t) | '&k=' | lastid | ba )
       RETURN
     END
   END
@@ -1386,6 +1406,92 @@ BEGIN
   EXEC web.Trailer()
 END
 GO
+CREATE FN [handler].[/ApiKeys]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC web.Head( 'API keys' )
+  SELECT '<h1>API keys</h1>'
+
+  DECLARE u int, k int
+  DECLARE submit string SET submit = web.Form('$submit')
+  IF submit = 'Create'
+  BEGIN
+    SET u = Id FROM login.user WHERE Name = web.Form('user')
+    IF u = 0 SELECT '<p>Unknown user.'
+    ELSE
+    BEGIN
+      DECLARE days int, expires int SET days = PARSEINT( '0' | web.Form('days') )
+      IF days > 0 SET expires = date.Ticks() + days * 24 * 3600 * 1000000
+      DECLARE secret string SET secret = SUBSTRING( '' | RANDOMBYTES(32), 3, 64 )
+      INSERT INTO login.ApiKey( User, Name, HashedSecret, Scopes, Expires, Schemas ) 
+      VALUES ( u, web.Form('name'), SHAHASH( secret ), web.Form('scopes'), expires, web.Form('schemas') )
+      SELECT '<p>New API key ( it will not be shown again ):<pre>' | LASTID() | '_' | secret | '</pre>'
+    END
+  END
+  ELSE IF submit = 'Delete'
+    DELETE FROM login.ApiKey WHERE Id = PARSEINT( web.Form('k') )
+
+  SELECT '<table><tr><th>Id<th>Name<th>User<th>Scopes<th>Replicated schemas<th>Expires<th></tr>'
+  DECLARE name string, uname string, scopes string, schemas string, exp int
+  FOR k = Id, name = Name, u = User, scopes = Scopes, schemas = Schemas, exp = Expires FROM login.ApiKey
+  BEGIN
+    SET uname = Name FROM login.user WHERE Id = u
+    SELECT '<tr><td>' | k | '<td>' | htm.Encode(name) | '<td>' | htm.Encode(uname) | '<td>' | htm.Encode(scopes)
+      | '<td>' | CASE WHEN schemas = '' THEN 'All' ELSE htm.Encode(schemas) END
+      | '<td>' | CASE WHEN exp = 0 THEN 'Never' ELSE date.MicroSecToString(exp) END
+      | '<td><form method=post><input type=hidden name=k value=' | k | '><input name=\"$submit\" type=submit value=Delete></form>'
+  END
+  SELECT '</table>'
+
+  SELECT '<h3>New API key</h3><form method=post>'
+    | '<p>Name <input name=name> User <input name=user>'
+    | '<p>Scopes <input name=scopes size=50 placeholder=\"/GetTransactions /GetSnapshot\"> ( space separated handler paths, or * for all )'
+    | '<p>Replicated schemas <input name=schemas size=50 placeholder=\"web handler\"> ( space separated schemas or schema.table, blank for all )'
+    | '<p>Expires after <input name=days size=5> days ( blank for never )'
+    | '<p><input name=\"$submit\" type=submit value=Create></form>'
+  EXEC web.Trailer()
+END
+GO
+CREATE FN [handler].[/AuditEvents]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  DECLARE uname string SET uname = web.Query('user')
+  DECLARE tname string SET tname = web.Query('table')
+  DECLARE fromdate string SET fromdate = web.Query('from')
+  DECLARE todate string SET todate = web.Query('to')
+
+  DECLARE fu int, ft int, fstart int, fend int
+  IF uname != '' SET fu = Id FROM login.user WHERE Name = uname
+  IF tname != '' SET ft = Id FROM sys.Table WHERE REPLACE( REPLACE( sys.TableName( Id ), '[', '' ), ']', '' ) = tname
+  IF fromdate != '' SET fstart = date.StringToDays( fromdate ) * 86400 * 1000000
+  IF todate != '' SET fend = ( date.StringToDays( todate ) + 1 ) * 86400 * 1000000
+
+  EXEC web.Head( 'Audit Events' )
+  SELECT '<h1>Audit Events</h1><form>'
+    | 'User <input name=user size=10 value=' | htm.Attr(uname) | '>'
+    | ' Table <input name=table size=15 placeholder=schema.name value=' | htm.Attr(tname) | '>'
+    | ' From <input name=from size=12 placeholder=\"Feb 2 2020\" value=' | htm.Attr(fromdate) | '>'
+    | ' To <input name=to size=12 value=' | htm.Attr(todate) | '>'
+    | ' <input type=submit value=Filter></form>'
+  
+  SELECT '<table><tr><th>Time<th>User<th>Ip<th>Path<th>Table<th>Row<th>Before<th>After</tr>'
+  DECLARE e int, u int, name string
+  FOR e = Id, u = User FROM audit.Event 
+    WHERE ( uname = '' OR User = fu ) AND ( tname = '' OR Table = ft ) 
+      AND ( fromdate = '' OR Time >= fstart ) AND ( todate = '' OR Time < fend )
+    ORDER BY Id DESC
+  BEGIN
+    SET name = Name FROM login.user WHERE Id = u
+    SELECT '<tr><td>' | date.MicroSecToString(Time) | '<td>' | htm.Encode(name) | '<td>' | htm.Encode(Ip) | '<td>' | htm.Encode(Path) 
+      | '<td>' | sys.TableName(Table) | '<td>' | RowId | '<td>' | htm.Encode(Before) | '<td>' | htm.Encode(After)
+    FROM audit.Event WHERE Id = e
+  END
+  SELECT '</table>'
+  EXEC web.Trailer()
+END
+GO
 CREATE FN [handler].[/BrowseColInfo]() AS 
 BEGIN 
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -1465,6 +1571,49 @@ BEGIN
   EXEC web.Trailer()
 END
 GO
+CREATE FN [handler].[/Dkim]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC web.Head( 'DKIM' )
+  SELECT '<h1>DKIM</h1>'
+
+  DECLARE a int SET a = PARSEINT( '0' | web.Form('a') )
+  DECLARE submit string SET submit = web.Form('$submit')
+  IF submit = 'Save' OR submit = 'Generate key'
+  BEGIN
+    DECLARE sel string SET sel = web.Form('selector')
+    IF sel = '' SET sel = 'rustweb'
+    DECLARE ae int SET ae = audit.Start( cu, sys.TableId( 'email', 'SmtpAccount' ), a )
+    UPDATE email.SmtpAccount SET dkimdomain = web.Form('domain'), dkimselector = sel WHERE Id = a
+    EXEC audit.Finish( ae, 0 )
+    /* The key is generated after this transaction, and saved by email.SetDkimKey. */
+    DECLARE x int
+    IF submit = 'Generate key' SET x = DKIMKEY( a, cu )
+    EXEC web.Redirect( '/Dkim' )
+    RETURN
+  END
+
+  SELECT '<p>Emails sent using an SmtpAccount with a DKIM domain are signed. '
+    | 'Publish the DNS TXT record shown, then set the domain ( the key can also be pasted into the dkimkey column of email.SmtpAccount ).'
+  SELECT '<table><tr><th>Account<th>Server<th>Domain<th>Selector<th>DNS TXT record<th></tr>'
+  DECLARE server string, domain string, selector string, key string, record string
+  FOR a = Id, server = server, domain = dkimdomain, selector = dkimselector, key = dkimkey FROM email.SmtpAccount
+  BEGIN
+    SET record = DKIMRECORD( key )
+    SELECT '<tr><td>' | email.SmtpAccountName( a ) | '<td>' | htm.Encode( server )
+      | '<td><input form=f' | a | ' name=domain value=' | htm.Attr( domain ) | '>'
+      | '<td><input form=f' | a | ' name=selector size=10 value=' | htm.Attr( CASE WHEN selector = '' THEN 'rustweb' ELSE selector END ) | '>'
+      | '<td>' | CASE WHEN record = '' THEN CASE WHEN key = '' THEN 'No key' ELSE 'Invalid key' END
+         ELSE '<b>' | htm.Encode( selector | '._domainkey.' | domain ) | '</b><br><textarea rows=5 cols=60 readonly>' 
+           | htm.Encode( record ) | '</textarea>' END
+      | '<td><form id=f' | a | ' method=post><input type=hidden name=a value=' | a | '>'
+      | '<input name=\"$submit\" type=submit value=Save> <input name=\"$submit\" type=submit value=\"Generate key\"></form>'
+  END
+  SELECT '</table>'
+  EXEC web.Trailer()
+END
+GO
 CREATE FN [handler].[/EditFile]() AS
 BEGIN
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -1497,8 +1646,11 @@ BEGIN
   DECLARE def string, ex string SET def = web.Form('def')
   IF def != '' 
   BEGIN
+    DECLARE fid int SET fid = Id FROM sys.Function WHERE Schema = sid AND Name = n
+    DECLARE ae int SET ae = audit.Start( cu, sys.TableId( 'sys', 'Function' ), fid )
     EXECUTE( 'ALTER FN ' | sys.Dot(s,n) | def )
     SET ex = EXCEPTION()
+    EXEC audit.Finish( ae, 0 )
   END
   ELSE SET def = Def FROM sys.Function WHERE Schema = sid AND Name = n 
   EXEC web.Head( 'Edit ' | n )
@@ -1522,10 +1674,12 @@ BEGIN
   DECLARE submit string SET submit = web.Form( '$submit' )
   IF submit != '' 
   BEGIN
+    DECLARE ae int SET ae = audit.Start( cu, t, k )
     IF submit = 'Save'
     BEGIN
       EXECUTE( browse.UpdateSql( t, k ) ) 
       SET ex = EXCEPTION()
+      EXEC audit.Finish( ae, 0 )
       IF ex = '' 
       BEGIN
         EXEC web.Redirect( browse.backurl() )
@@ -1535,6 +1689,7 @@ BEGIN
     ELSE IF submit = 'Delete'
     BEGIN
       EXECUTE( 'DELETE FROM ' | sys.TableName( t ) | ' WHERE Id =' | k )
+      EXEC audit.Finish( ae, 0 )
       EXEC web.Redirect( browse.backurl() )
       RETURN
     END      
@@ -1556,19 +1711,56 @@ BEGIN
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
   DECLARE sql string SET sql = web.Form('sql')
+  DECLARE readonly bool SET readonly = web.Query('readonly') = '1'
+
+  /* Restrictions from the user's role, role 0 is unrestricted. 
+     Statements is a space separated list of allowed statement types ( e.g. SELECT EXEC ), or * for all. */
+  DECLARE role int, allowed string, rowlimit int, roleonly bool
+  SET role = Role FROM login.user WHERE Id = cu
+  SET allowed = '*', rowlimit = 1000
+  IF role != 0 SET allowed = ''
+  SET allowed = Statements, roleonly = ReadOnly, rowlimit = CASE WHEN RowLimit > 0 THEN RowLimit ELSE rowlimit END
+  FROM login.Role WHERE Id = role
+
   EXEC web.Head( 'Execute' )
   SELECT 
      '<p><form method=post>'
-     | 'SQL to <input type=submit value=Execute>'
+     | 'SQL to <input type=submit value=Execute' | CASE WHEN roleonly THEN ' disabled' ELSE '' END | '>'
+     | ' <input type=submit formaction=\"/Execute?readonly=1\" value=\"Execute read-only\">'
+     | ' <input type=checkbox id=confirm name=confirm><label for=confirm>Confirm DROP</label>'
+     | ' <a href=\"/ShowTable?s=audit&n=SqlHistory\">History</a>'
      | '<br><textarea name=sql rows=20 cols=100' | CASE WHEN sql='' THEN ' placeholder=\"Enter SQL here. See Manual for details.\"' ELSE '' END | '>' | htm.Encode(sql) | '</textarea>' 
      | '</form>' 
   IF sql != '' 
   BEGIN
-    -- EXEC SETMODE( 1 ) -- Causes result tables to be displayed as HTML tables
-    EXECUTE( sql ) 
-    -- EXEC SETMODE( 0 )
-    DECLARE ex string SET ex = EXCEPTION()
+    DECLARE ex string, rows int, x int
+    IF roleonly AND NOT readonly 
+      SET ex = 'Your role may only execute read-only.'
+    ELSE IF allowed != '*' AND SQLVERBS( sql, allowed ) != ''
+      SET ex = 'Statement types not allowed for your role : ' | SQLVERBS( sql, allowed )
+    ELSE IF SQLVERBS( sql, 'SELECT INSERT UPDATE DELETE CREATE ALTER RENAME EXEC CHECK' ) != '' AND web.Form('confirm') = ''
+      SET ex = 'DROP ( and EXECUTE ) must be confirmed, tick Confirm DROP and execute again.'
+    ELSE
+    BEGIN
+      IF NOT readonly
+      BEGIN
+        DECLARE ae int SET ae = audit.Start( cu, 0, 0 )
+        UPDATE audit.Event SET After = sql WHERE Id = ae
+      END
+      -- Called before the SQL is run as well as after, so the SQL cannot record replica status ( see SQLHISTORY ).
+      SET x = SQLHISTORY( cu, sql, readonly, '', 0 )
+      SET x = ROWLIMIT( rowlimit )
+      -- EXEC SETMODE( 1 ) -- Causes result tables to be displayed as HTML tables
+      EXECUTE( sql ) 
+      -- EXEC SETMODE( 0 )
+      SET rows = ROWLIMIT( 0 )
+      SET ex = EXCEPTION()
+    END
     IF ex != '' SELECT '<p>Error : ' | htm.Encode(ex)
+    IF rows > rowlimit SELECT '<p>Output truncated, ' | rows | ' rows selected, limit is ' | rowlimit | '.'
+
+    -- History is recorded as a separate transaction, so read-only and failed statements are also recorded.
+    SET x = SQLHISTORY( cu, sql, readonly, ex, rows )
   END
   SELECT '<p>Example SQL:'
      | '<br>SELECT dbo.CustName(Id) AS Name, Age FROM dbo.Cust'
@@ -1605,19 +1797,133 @@ BEGIN
     SELECT '<p>Filename=' | FILEATTR(0,2) | ' ContentType=' | FILEATTR(0,1)
     DECLARE content binary SET content =  FILECONTENT(0)
     
+    DECLARE ae int SET ae = audit.Start( cu, sys.TableId( 'web', 'File' ), 0 )
     INSERT INTO web.File( Path, ContentType, ContentLength, Content )
     VALUES ( '/Uploads/' | FILEATTR(0,2), FILEATTR(0,1), BINLEN(content), content )
+    EXEC audit.Finish( ae, LASTID() )
   END
   SELECT '<form method=post enctype=\"multipart/form-data\"><p><Input name=file type=file><p><input name=submit type=submit value=Upload></form>'
   EXEC web.Trailer()
 END
 GO
+CREATE FN [handler].[/ForgotPassword]() AS 
+BEGIN 
+  DECLARE name string SET name = web.Form('username')
+  IF name != ''
+  BEGIN
+    DECLARE now int SET now = date.Ticks()
+    DELETE FROM login.Reset WHERE Expires < now
+
+    DECLARE u int, address string
+    SET u = Id, address = Email FROM login.user WHERE Name = name
+    IF u > 0 AND address != ''
+    BEGIN
+      -- Token is only stored hashed, the email contains the only copy of the token.
+      DECLARE token string SET token = '' | RANDOMBYTES(16)
+      INSERT INTO login.Reset( User, HashedToken, Expires ) 
+      VALUES ( u, login.hash( token ), now + 3600 * 1000000 ) -- Token expires after one hour.
+      EXEC login.ResetEmail( LASTID(), token, address )
+    END
+    -- Same response whether or not the user exists.
+    EXEC web.pubhead( 'Password reset' )
+    SELECT '<p>If the user name is known, an email has been sent with a link to reset the password.'
+    EXEC web.pubtrail()
+  END
+  ELSE
+  BEGIN
+    EXEC web.pubhead( 'Password reset' )
+    SELECT '<form method=post>User Name <input name=username><br><input type=submit value=\"Send password reset email\"></form>'
+    EXEC web.pubtrail()
+  END
+END
+GO
+CREATE FN [handler].[/GetChecksums]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* 
+     Checksums used by a replica to verify it matches this server ( see TABLECHECKSUM ).
+     The first line is the Id of the last transaction, then there is a line for each table with schema, name, row count and hash ( separated by tabs ).
+     The log schema ( which is not replicated exactly ) and sys tables other than sys.Function are excluded, as are tables not allowed by a replication filter.
+  */
+  EXEC web.SetContentType( 'text/plain;charset=utf-8' )
+  DECLARE filter string SET filter = login.ReplicationFilter()
+  SELECT log.LastId() | '
+'
+  DECLARE t int, s int, sname string, tname string, fn int
+  SET fn = sys.TableId( 'sys', 'Function' )
+  FOR t = Id, s = Schema, tname = Name FROM sys.Table
+  BEGIN
+    SET sname = sys.SchemaName( s )
+    IF t = fn OR sname != 'sys' AND sname != 'log' AND ( filter = '' OR log.Allowed( t, filter ) )
+      SELECT sname | '\t' | tname | '\t' | REPLACE( TABLECHECKSUM( sname, tname ), ' ', '\t' ) | '
+'
+  END
+END
+GO
+CREATE FN [handler].[/GetSnapshot]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* 
+     Get a chunk of a consistent snapshot of the database pages, used to initialise a new replica.
+     id is zero to start a new snapshot, start is the first page of the chunk ( see SNAPSHOT ).
+  */
+  DECLARE filter string SET filter = login.ReplicationFilter()
+  IF filter != ''
+  BEGIN
+    /* A filtered replica gets a script instead ( see log.ScriptFiltered ). */
+    EXEC web.SetContentType( 'text/plain;charset=utf-8' )
+    EXEC log.ScriptFiltered( filter )
+    RETURN
+  END
+
+  DECLARE id int, start int
+  IF web.Query('id') != '' SET id = PARSEINT( web.Query('id') )
+  IF web.Query('start') != '' SET start = PARSEINT( web.Query('start') )
+  DECLARE x int SET x = SNAPSHOT( id, start )
+END
+GO
+CREATE FN [handler].[/GetTableScript]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* 
+     Script to replace all the rows of table n in schema s, used to repair a replica ( see /GetChecksums ).
+     The first line is the Id of the last transaction.
+  */
+  DECLARE sid int, t int
+  SET sid = Id FROM sys.Schema WHERE Name = web.Query('s')
+  SET t = Id FROM sys.Table WHERE Schema = sid AND Name = web.Query('n')
+  DECLARE filter string SET filter = login.ReplicationFilter()
+  IF t = 0 OR filter != '' AND NOT log.Allowed( t, filter )
+  BEGIN
+    DECLARE status int SET status = STATUSCODE( 404 )
+    SELECT 'Table not found.'
+    RETURN
+  END
+  EXEC web.SetContentType( 'text/plain;charset=utf-8' )
+  SELECT log.LastId() | '
+DELETE FROM ' | sys.TableName(t) | ' WHERE true
+GO'
+  EXEC sys.ScriptData(t,1)
+END
+GO
 CREATE FN [handler].[/GetTransaction]() AS 
 BEGIN 
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
 
   DECLARE k int SET k = PARSEINT( web.Query('k') )
 
+  IF login.ReplicationFilter() != ''
+  BEGIN
+    DECLARE status int SET status = STATUSCODE( 403 )
+    SELECT 'Use /GetTransactions for filtered replication.'
+    RETURN
+  END
+
+  IF log.ReplicaPoll( k ) RETURN
+
   DECLARE id int, d binary
 
   SET id = Id, d = data FROM log.Transaction WHERE Id = k
@@ -1630,6 +1936,57 @@ BEGIN
   END
 END
 GO
+CREATE FN [handler].[/GetTransactions]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* 
+     Get a batch of up to n ( default 1000 ) transactions starting at Id k, each prefixed by its length ( see FRAMEADD ). 
+     The batch is limited to about 10MB. If pack=1 the batch is compressed ( see BINPACK ).
+     If the API key has a replication filter, the changes to the allowed tables are sent instead of the transactions ( see log.Change ).
+  */
+  DECLARE k int SET k = PARSEINT( web.Query('k') )
+  DECLARE n int SET n = 1000
+  IF web.Query('n') != '' SET n = PARSEINT( web.Query('n') )
+  DECLARE filter string SET filter = login.ReplicationFilter()
+
+  IF log.ReplicaPoll( k ) RETURN
+
+  DECLARE i int, id int, d binary, size int, reseed int
+  SET i = k
+  WHILE i < k + n AND size < 10000000
+  BEGIN
+    SET id = 0
+    SET id = Id, d = data FROM log.Transaction WHERE Id = i
+    IF id != i BREAK
+    IF filter != ''
+    BEGIN
+      SET reseed = 0
+      SET reseed = Id FROM log.Change WHERE Transaction = i AND Table = 0
+      IF reseed != 0 BREAK
+      SET d = log.FilteredRecord( i, filter )
+    END
+    SET size = FRAMEADD( d )
+    SET i = i + 1
+  END
+
+  IF i = k AND reseed != 0
+  BEGIN
+    DECLARE status int SET status = STATUSCODE( 410 )
+    SELECT 'Transaction ' | k | ' changed the database schema, the filtered replica must be re-seeded.'
+    RETURN
+  END
+
+  IF i = k 
+  BEGIN
+    DECLARE dummy int SET dummy = TRANSWAIT()
+  END
+  ELSE IF web.Query('pack') = '1'
+    SELECT BINPACK( FRAMES() )
+  ELSE
+    SELECT FRAMES()
+END
+GO
 CREATE FN [handler].[/ListFile]() AS
 BEGIN
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -1649,6 +2006,7 @@ BEGIN
   EXEC web.Head('Logins')
 
   SELECT '<p>' | Name | ' <a href=\"/SetPassword?k=' | Id | '\">Set Password</a>'
+    | ' <a href=\"/TotpEnrol?k=' | Id | '\">Two-factor</a>' | CASE WHEN TotpRequired THEN ' (enabled)' ELSE '' END
   FROM login.user
   ORDER BY Name
 
@@ -1659,6 +2017,7 @@ CREATE FN [handler].[/Logout]() AS
 BEGIN 
     EXEC web.SetCookie( 'uid', '', '' )
     EXEC web.SetCookie( 'hpw', '', '' )
+    EXEC web.SetCookie( 'tfa', '', '' )
     EXEC web.Head( 'Logout' )
     SELECT '<p>Logged out.'
     EXEC web.Trailer()
@@ -1832,6 +2191,10 @@ BEGIN
 <h3>System</h3>
 <p><a href=/Execute>Execute SQL</a>
 <p><a href=/ListLogins>Logins</a>
+<p><a href=/ApiKeys>API keys</a>
+<p><a href=/AuditEvents>Audit trail</a>
+<p><a href=/Replication>Replication</a>
+<p><a href=/Dkim>DKIM</a>
 <p><a href=/ListFile>Files</a>
 <p><a href=/FileUpload>File Upload</a>
 <p><a target=_blank href=/ScriptAll?mode=1>Script entire database</a> 
@@ -1869,6 +2232,112 @@ BEGIN
   EXEC web.Trailer()
 END
 GO
+CREATE FN [handler].[/Promote]() AS
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  EXEC web.Head( 'Promote replica' )
+  SELECT '<h1>Promote replica</h1>'
+  IF web.Form('confirm') = ''
+  BEGIN
+    SELECT '<p>Promoting a replica makes it the master: it stops fetching transactions from the current master, '
+      | 'starts logging its own transactions, and starts sending email and running timed jobs.'
+      | '<p>Only do this if the master has failed. Other replicas can then be re-started with --rep set to this server.'
+      | '<form method=post><p><input type=checkbox id=confirm name=confirm><label for=confirm>Confirm promotion</label>'
+      | ' <input type=submit value=Promote></form>'
+  END
+  ELSE IF PROMOTE() = 1
+  BEGIN
+    DECLARE ae int SET ae = audit.Start( cu, 0, 0 )
+    UPDATE audit.Event SET After = 'Replica promoted to master' WHERE Id = ae
+    SELECT '<p>This server is now the master. Restart it without the --rep option, so it remains the master.'
+  END
+  ELSE
+    SELECT '<p>This server is already the master.'
+  EXEC web.Trailer()
+END
+GO
+CREATE FN [handler].[/ReplicaVerified]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* 
+     Records the result of a replica checking it matches this server at transaction k ( see /GetChecksums ). 
+     diverged is a space separated list of the tables that did not match. This is not logged, so log.Replica is not replicated.
+  */
+  DECLARE x int SET x = REPLICAVERIFIED( web.Query('name'), PARSEINT( web.Query('k') ), web.Query('diverged') )
+END
+GO
+CREATE FN [handler].[/Replication]() AS
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  DECLARE last int SET last = log.LastId()
+  DECLARE now int SET now = date.Ticks()
+
+  EXEC web.Head( 'Replication' )
+  SELECT '<h1>Replication</h1><p>Last transaction Id=' | last
+  SELECT '<p><table><tr><th>Replica<th>Ip<th>Last seen<th>Seconds ago<th>Applied Id<th>Lag ( transactions )<th>Verified<th>At Id<th>Diverged tables<th>'
+  SELECT '<tr><td>' | htm.Encode(Name) | '<td>' | htm.Encode(Ip) | '<td>' | date.MicroSecToString(LastSeen) 
+    | '<td align=right>' | ( now - LastSeen ) / 1000000 | '<td align=right>' | Applied | '<td align=right>' | ( last - Applied )
+    | '<td>' | CASE WHEN Verified = 0 THEN '' ELSE date.MicroSecToString(Verified) END
+    | '<td align=right>' | CASE WHEN Verified = 0 THEN '' ELSE '' | VerifiedId END
+    | '<td>' | CASE WHEN Diverged = '' THEN '' ELSE '<b>' | htm.Encode(Diverged) | '</b>' END
+    | '<td>' | CASE WHEN Reseed THEN '<b>Re-seed required</b>' ELSE '' END
+  FROM log.Replica ORDER BY Name
+  SELECT '</table>'
+  SELECT '<p>Replicas report progress each time they poll for a transaction ( at least every 10 minutes ).'
+  SELECT '<p>Replicas periodically verify they match this server, by comparing table checksums at the same transaction Id.'
+  SELECT '<p>Transactions applied by every replica are deleted hourly by log.Prune ( edit it to change the retention settings ).'
+  SELECT '<p>If the master has failed, a replica can be made the master using its <a href=/Promote>Promote</a> page.'
+  EXEC web.Trailer()
+END
+GO
+CREATE FN [handler].[/ReplicationStatus]() AS
+BEGIN
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  /* Plain text status for monitoring, on a replica this is the Id of the last transaction applied. */
+  EXEC web.SetContentType( 'text/plain;charset=utf-8' )
+  SELECT 'last=' | log.LastId()
+END
+GO
+CREATE FN [handler].[/ResetPassword]() AS 
+BEGIN 
+  DECLARE r int SET r = PARSEINT( web.Query('r') )
+  DECLARE token string SET token = web.Query('t')
+
+  DECLARE u int, htok binary, expires int
+  SET u = User, htok = HashedToken, expires = Expires FROM login.Reset WHERE Id = r
+
+  EXEC web.pubhead( 'Password reset' )
+  IF u = 0 OR date.Ticks() > expires OR '' | htok != '' | login.hash( token )
+  BEGIN
+    SELECT '<p>This password reset link is invalid or has expired.'
+  END
+  ELSE
+  BEGIN
+    DECLARE pw string SET pw = web.Form('pw')
+    DECLARE hpw binary, old binary
+    SET old = HashedPassword FROM login.user WHERE Id = u
+    IF pw != '' SET hpw = login.hash(pw|u)
+    -- An unchanged password is refused, as existing sessions would still be valid.
+    IF pw != '' AND READONLY() = 0 AND '' | hpw != '' | old
+    BEGIN
+      -- Changing HashedPassword ( and clearing TotpSession ) logs out existing sessions, as the cookies no longer match.
+      UPDATE login.user SET HashedPassword = hpw, TotpSession = 0x WHERE Id = u
+      DELETE FROM login.Reset WHERE User = u
+      SELECT '<p>Password has been reset. <a href=/Menu>Login</a>'
+    END
+    ELSE
+    BEGIN
+      IF pw != '' AND '' | hpw = '' | old SELECT '<p>The new password must be different from the current password.'
+      SELECT '<form method=post><p>Enter new password: <input type=password name=pw><input type=submit></form>'
+    END
+  END
+  EXEC web.pubtrail()
+END
+GO
 CREATE FN [handler].[/Rtest]() AS 
 BEGIN 
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -1913,7 +2382,9 @@ BEGIN
   DECLARE pw string SET pw = web.Form('pw')
   IF pw != '' 
   BEGIN
+    DECLARE ae int SET ae = audit.Start( cu, sys.TableId( 'login', 'user' ), k )
     UPDATE login.user SET HashedPassword = login.hash(pw|k) WHERE Id = k
+    EXEC audit.Finish( ae, 0 )
     EXEC web.Head( 'Password Set')
     SELECT '<p>Password set'
     EXEC web.Trailer()
@@ -1990,6 +2461,75 @@ BEGIN
   EXEC web.Trailer()
 END
 GO
+CREATE FN [handler].[/TotpEnrol]() AS 
+BEGIN 
+  DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
+
+  DECLARE k int SET k = PARSEINT( web.Query('k') )
+  DECLARE name string, secret binary, required bool
+  SET name = Name, secret = TotpSecret, required = TotpRequired FROM login.user WHERE Id = k
+
+  EXEC web.Head( 'Two-factor authentication' )
+  SELECT '<h1>Two-factor authentication for ' | htm.Encode(name) | '</h1>'
+
+  DECLARE submit string SET submit = web.Form('$submit')
+  IF submit = 'New secret'
+  BEGIN
+    SET secret = RANDOMBYTES(20), required = false
+    UPDATE login.user SET TotpSecret = secret, TotpRequired = false, TotpStep = 0 WHERE Id = k
+    DELETE FROM login.Recovery WHERE User = k
+  END
+  ELSE IF submit = 'Disable'
+  BEGIN
+    SET secret = 0x, required = false
+    UPDATE login.user SET TotpSecret = secret, TotpRequired = false WHERE Id = k
+    DELETE FROM login.Recovery WHERE User = k
+  END
+  ELSE IF submit = 'Confirm'
+  BEGIN
+    IF LEN( web.Form('code') ) = 6 AND login.CheckCode( k, web.Form('code') )
+    BEGIN
+      UPDATE login.user SET TotpRequired = true WHERE Id = k
+      SET required = true
+      IF k = cu -- Keep the current session.
+      BEGIN
+        DECLARE hpw binary SET hpw = HashedPassword FROM login.user WHERE Id = k
+        EXEC login.SetSession( k, hpw )
+      END
+
+      SELECT '<p>Recovery codes ( each can be used once instead of an authenticator code, they will not be shown again ):<pre>'
+      DECLARE i int, code string
+      WHILE i < 8
+      BEGIN
+        SET code = SUBSTRING( '' | RANDOMBYTES(5), 3, 10 )
+        INSERT INTO login.Recovery( User, HashedCode ) VALUES ( k, login.hash( code ) )
+        SELECT code | '
+'
+        SET i = i + 1
+      END
+      SELECT '</pre>'
+    END
+    ELSE SELECT '<p>Code not valid, please try again.'
+  END
+
+  IF required
+    SELECT '<p>Two-factor authentication is enabled.'
+  ELSE IF BINLEN( secret ) > 0
+  BEGIN
+    DECLARE uri string SET uri = 'otpauth://totp/' | web.UrlEncode( 'rustweb:' | name ) | '?secret=' | TOTPKEY( secret ) | '&issuer=rustweb'
+    SELECT '<p>Scan the QR code below with an authenticator app, or enter the secret ' | TOTPKEY( secret ) | ' manually.'
+      | '<p>' | QRSVG( uri ) | '<p>' | htm.Encode( uri )
+      | '<form method=post><p>Enter code from authenticator app to confirm: <input name=code autocomplete=off> '
+      | '<input name=\"$submit\" type=submit value=Confirm></form>'
+  END
+  ELSE
+    SELECT '<p>Two-factor authentication is not enabled.'
+
+  SELECT '<form method=post><p><input name=\"$submit\" type=submit value=\"New secret\"> '
+    | CASE WHEN BINLEN( secret ) > 0 THEN '<input name=\"$submit\" type=submit value=Disable>' ELSE '' END | '</form>'
+  EXEC web.Trailer()
+END
+GO
 CREATE FN [handler].[/VerifyDB]() AS
 BEGIN
   DECLARE cu int SET cu = login.get(1) IF cu = 0 RETURN
@@ -2063,19 +2603,61 @@ GO
 CREATE SCHEMA [email]
 CREATE TABLE [email].[Delayed]([msg] int,[error] string,[time] int) 
 GO
-CREATE TABLE [email].[Msg]([from] string,[to] string,[title] string,[body] string,[format] int(1),[account] int,[status] int) 
+CREATE TABLE [email].[Msg]([from] string,[to] string,[title] string,[body] string,[format] int(1),[account] int,[status] int,[text] string,[cc] string,[bcc] string,[replyto] string,[headers] string,[attempts] int,[response] string,[messageid] string) 
 GO
 CREATE TABLE [email].[Queue]([msg] int) 
 GO
 CREATE TABLE [email].[SendError]([msg] int,[error] string,[time] int) 
 GO
-CREATE TABLE [email].[SmtpAccount]([server] string,[username] string,[password] string) 
+CREATE TABLE [email].[SmtpAccount]([server] string,[username] string,[password] string,[port] int,[security] int(1),[mechanism] string,[timeout] int,[transport] int(1),[connections] int,[ratelimit] int,[retrybase] int,[retrycap] int,[maxattempts] int,[dkimdomain] string,[dkimselector] string,[dkimkey] string) 
+GO
+CREATE TABLE [email].[Attachment]([msg] int,[filename] string,[contenttype] string,[content] binary,[file] int) 
+GO
+CREATE INDEX [ByMsg] ON [email].[Attachment]([msg])
+GO
+CREATE TABLE [email].[Recipient]([msg] int,[kind] int(1),[address] string) 
+GO
+CREATE INDEX [ByMsg] ON [email].[Recipient]([msg])
+GO
+CREATE TABLE [email].[Attempt]([msg] int,[time] int,[status] int(1),[response] string) 
+GO
+CREATE INDEX [ByMsg] ON [email].[Attempt]([msg])
+GO
+CREATE TABLE [email].[Template]([name] string,[subject] string,[html] string,[text] string,[from] string,[account] int) 
+GO
+CREATE INDEX [ByName] ON [email].[Template]([name])
+GO
+CREATE FN [email].[LogRecipientError]( id int, error string ) AS
+BEGIN
+  -- The email is still sent to the other recipients.
+  INSERT INTO email.SendError( msg, error, time )
+  VALUES ( id, error, date.Ticks() )
+END
 GO
 CREATE FN [email].[LogSendError]( id int, retry int, error string ) AS
 
 BEGIN
   DELETE FROM email.Queue WHERE msg = id
 
+  -- Give up after the maximum attempts for the account.
+  IF retry != 0
+  BEGIN
+    DECLARE acc int, n int, most int
+    SET acc = account, n = attempts FROM email.Msg WHERE Id = id
+    SET most = maxattempts FROM email.SmtpAccount WHERE Id = acc
+    IF most > 0 AND n >= most
+    BEGIN
+      SET retry = 0
+      SET error = error | ' ( gave up after ' | n | ' attempts )'
+    END
+  END
+
+  -- Status 3 is retrying, 4 is failed.
+  DECLARE st int SET st = CASE WHEN retry = 0 THEN 4 ELSE 3 END
+  UPDATE email.Msg SET status = st, response = error WHERE Id = id
+  INSERT INTO email.Attempt( msg, time, status, response )
+  VALUES ( id, date.Ticks(), st, error )
+
   IF retry = 0
   BEGIN
     INSERT INTO email.SendError( msg, error, time )
@@ -2116,30 +2698,34 @@ CREATE FN [email].[Retry]() AS
 BEGIN 
   DECLARE now int SET now = date.Ticks()
  
-  -- Find a Delayed email that is due to be sent.
-  -- Transient failures are retried after 600 seconds = ten minutes. 
-  DECLARE id int, t int, r int
-  FOR id = Id, t = time + 600 * 1000000 FROM email.Delayed 
+  -- Requeue all Delayed emails that are due to be sent ( see email.RetryDelay ).
+  DECLARE id int, m int, t int, r int, last int, requeued int
+  WHILE true
   BEGIN
-    IF now >= t
+    SET r = 0
+    FOR id = Id, m = msg, t = time FROM email.Delayed WHERE Id > last
     BEGIN
-      SET r = id
-      BREAK
+      IF now >= t + email.RetryDelay(m)
+      BEGIN
+        SET r = id
+        BREAK
+      END
     END
+    IF r = 0 BREAK
+    SET last = r
+    DELETE FROM email.Delayed WHERE Id = r
+    INSERT INTO email.Queue( msg ) VALUES ( m )
+    SET requeued = 1
   END
 
-  IF r != 0
+  IF requeued = 1
   BEGIN
-    DECLARE m int
-    SET m = msg FROM email.Delayed WHERE Id = r
-    DELETE FROM email.Delayed WHERE Id = r
-    INSERT INTO email.Queue( msg ) VALUES ( m )
     DECLARE dummy int SET dummy = EMAILTX()
   END
 
   -- Calculate time to for next call to email.Retry.
   DECLARE next int SET next = now + 24 * 3600 * 1000000
-  FOR t = time + 600 * 1000000 FROM email.Delayed
+  FOR t = time + email.RetryDelay(msg) FROM email.Delayed
   BEGIN
     IF t < next SET next = t
   END
@@ -2151,14 +2737,83 @@ BEGIN
 
 END
 GO
-CREATE FN [email].[Sent](id int) AS
+CREATE FN [email].[RetryDelay]( m int ) RETURNS int AS
+BEGIN
+  /* Delay ( in microseconds ) before a temporary send error is retried.
+     The delay doubles after each attempt, starting at retrybase seconds ( default 600 ), up to retrycap seconds ( default one day ).
+  */
+  DECLARE acc int, n int, base int, cap int
+  SET acc = account, n = attempts FROM email.Msg WHERE Id = m
+  SET base = retrybase, cap = retrycap FROM email.SmtpAccount WHERE Id = acc
+  IF base = 0 SET base = 600
+  IF cap = 0 SET cap = 24 * 3600
+
+  SET result = base
+  WHILE n > 1 AND result < cap
+  BEGIN
+    SET result = result * 2
+    SET n = n - 1
+  END
+  IF result > cap SET result = cap
+  SET result = result * 1000000
+END
+GO
+CREATE FN [email].[SendTemplate]( template string, address string, params string ) RETURNS int AS
+BEGIN
+  /* Queues an email made from the named email.Template, and returns the email.Msg Id ( 0 if there is no such template ).
+     params is a list of name=value pairs separated by &, with each value encoded by web.UrlEncode, e.g. 'name=' | web.UrlEncode( name ).
+     In the template, {{name}} is replaced by the value ( HTML-escaped in the html ), {{{name}}} by the value without escaping.
+  */
+  DECLARE t int, sender string, acc int, subj string, h string, txt string
+  SET t = Id, sender = [from], acc = account, subj = subject, h = html, txt = text 
+  FROM email.Template WHERE name = template
+  IF t = 0 RETURN 0
+
+  -- If there is no html, a plain text email ( format 0 ) is sent.
+  SET subj = RENDER( subj, params, false ), txt = RENDER( txt, params, false )
+  IF h = '' 
+  BEGIN
+    INSERT INTO email.Msg( [from], [to], title, body, format, account, status )
+    VALUES ( sender, address, subj, txt, 0, acc, 0 )
+  END
+  ELSE
+  BEGIN
+    INSERT INTO email.Msg( [from], [to], title, body, format, account, status, text )
+    VALUES ( sender, address, subj, RENDER( h, params, true ), 1, acc, 0, txt )
+  END
+  DECLARE m int SET m = LASTID()
+  INSERT INTO email.Queue( msg ) VALUES ( m )
+  DECLARE dummy int SET dummy = EMAILTX()
+  RETURN m
+END
+GO
+CREATE FN [email].[Sending](id int) AS
+BEGIN
+  -- Status 1 is sending.
+  UPDATE email.Msg SET status = 1, attempts = attempts + 1 WHERE Id = id
+END
+GO
+CREATE FN [email].[Sent]( id int, reply string, mid string ) AS
 BEGIN
   DELETE FROM email.Queue WHERE msg = id
 
+  -- Status 2 is sent.
+  UPDATE email.Msg SET status = 2, response = reply, messageid = mid WHERE Id = id
+  INSERT INTO email.Attempt( msg, time, status, response )
+  VALUES ( id, date.Ticks(), 2, reply )
+
   -- Test retry.
   -- EXEC email.LogSendError( id, 1, 'Testing retry!' )
 END
 GO
+CREATE FN [email].[SetDkimKey]( a int, u int, key string ) AS
+BEGIN
+  /* Save a new DKIM key for SmtpAccount a, requested by user u ( see DKIMKEY ). */
+  DECLARE ae int SET ae = audit.Start( u, sys.TableId( 'email', 'SmtpAccount' ), a )
+  UPDATE email.SmtpAccount SET dkimkey = key WHERE Id = a
+  EXEC audit.Finish( ae, 0 )
+END
+GO
 CREATE FN [email].[SmtpAccountName](id int) RETURNS string AS
 BEGIN
   SET result = '' | id
@@ -2184,7 +2839,7 @@ GO
 INSERT INTO [email].[Delayed](Id,[msg],[error],[time]) VALUES 
 GO
 
-INSERT INTO [email].[Msg](Id,[from],[to],[title],[body],[format],[account],[status]) VALUES 
+INSERT INTO [email].[Msg](Id,[from],[to],[title],[body],[format],[account],[status],[text],[cc],[bcc],[replyto],[headers],[attempts],[response],[messageid]) VALUES 
 GO
 
 INSERT INTO [email].[Queue](Id,[msg]) VALUES 
@@ -2193,7 +2848,20 @@ GO
 INSERT INTO [email].[SendError](Id,[msg],[error],[time]) VALUES 
 GO
 
-INSERT INTO [email].[SmtpAccount](Id,[server],[username],[password]) VALUES 
+INSERT INTO [email].[SmtpAccount](Id,[server],[username],[password],[port],[security],[mechanism],[timeout],[transport],[connections],[ratelimit],[retrybase],[retrycap],[maxattempts],[dkimdomain],[dkimselector],[dkimkey]) VALUES 
+GO
+
+INSERT INTO [email].[Attachment](Id,[msg],[filename],[contenttype],[content],[file]) VALUES 
+GO
+
+INSERT INTO [email].[Recipient](Id,[msg],[kind],[address]) VALUES 
+GO
+
+INSERT INTO [email].[Attempt](Id,[msg],[time],[status],[response]) VALUES 
+GO
+
+INSERT INTO [email].[Template](Id,[name],[subject],[html],[text],[from],[account]) VALUES 
+(1,'PasswordReset','Password reset','<p>To reset your password, follow this link: <a href=\"{{link}}\">{{link}}</a><p>The link expires after one hour. If you did not request a password reset, ignore this email.','','noreply@example.com',1)
 GO
 
 --############################################
@@ -2251,7 +2919,147 @@ GO
 
 --############################################
 CREATE SCHEMA [login]
-CREATE TABLE [login].[user]([Name] string,[HashedPassword] binary) 
+CREATE TABLE [login].[user]([Name] string,[HashedPassword] binary,[Email] string,[TotpSecret] binary,[TotpRequired] bool,[TotpStep] int,[TotpSession] binary,[Role] int,[TotpFailures] int,[TotpFailTime] int) 
+GO
+CREATE TABLE [login].[Reset]([User] int,[HashedToken] binary,[Expires] int) 
+GO
+CREATE TABLE [login].[Recovery]([User] int,[HashedCode] binary) 
+GO
+CREATE TABLE [login].[ApiKey]([User] int,[Name] string,[HashedSecret] binary,[Scopes] string,[Expires] int,[Schemas] string) 
+GO
+CREATE TABLE [login].[Role]([Name] string,[Statements] string,[RowLimit] int,[ReadOnly] bool) 
+GO
+CREATE TABLE [login].[Pending]([User] int,[HashedToken] binary,[Expires] int) 
+GO
+CREATE FN [login].[RoleName](id int) RETURNS string AS
+BEGIN
+  SET result = Name FROM login.Role WHERE Id = id
+  IF result = '' SET result = '' | id
+END
+GO
+CREATE FN [login].[RoleSelect]( colId int, sel int ) RETURNS string AS
+BEGIN
+  DECLARE col string SET col = Name FROM sys.Column WHERE Id = colId
+
+  DECLARE opt string, options string
+
+  FOR opt = '<option ' | CASE WHEN Id = sel THEN ' selected' ELSE '' END 
+  | ' value=' | Id | '>' | htm.Encode( login.RoleName(Id) ) | '</option>'
+  FROM login.Role
+  ORDER BY Name
+  SET options |= opt
+
+  RETURN '<select id=\"' | col | '\" name=\"' | col | '\">' | options 
+    | '<option ' | CASE WHEN sel = 0 THEN ' selected' ELSE '' END | ' value=0>Unrestricted</option>'
+    | '</select>'
+END
+GO
+CREATE FN [login].[ApiKeyUser]( key string ) RETURNS int AS
+BEGIN
+  /* 
+     Get the user for an API key, checking expiry and scopes. 
+     Key format is Id of login.ApiKey, underscore, 64 hex digits.
+     Scopes is a space separated list of handler paths, or * for all handlers.
+     The secret is 256 random bits, so it is hashed with SHAHASH ( SHA-256 ) rather than login.hash ( which is slow, and is run for every request ).
+     A key with a replication filter ( Schemas ) can only be used for the replication handlers, as other handlers do not apply the filter.
+  */
+  DECLARE n int SET n = LEN( key ) - 65
+  IF n < 1 RETURN 0
+
+  DECLARE k int SET k = PARSEINT( SUBSTRING( key, 1, n ) )
+  DECLARE u int, h binary, scopes string, expires int, schemas string
+  SET u = User, h = HashedSecret, scopes = Scopes, expires = Expires, schemas = Schemas FROM login.ApiKey WHERE Id = k
+
+  IF u = 0 OR expires != 0 AND date.Ticks() > expires RETURN 0
+  IF '' | h != '' | SHAHASH( SUBSTRING( key, n + 2, 64 ) ) RETURN 0
+  DECLARE path string SET path = ' ' | web.Path() | ' '
+  SET scopes = ' ' | scopes | ' '
+  IF scopes != ' * ' AND REPLACE( scopes, path, '' ) = scopes RETURN 0
+  DECLARE replication string 
+  SET replication = ' /GetTransactions /GetTransaction /GetSnapshot /GetChecksums /GetTableScript /ReplicaVerified '
+  IF schemas != '' AND REPLACE( replication, path, '' ) = replication RETURN 0
+  RETURN u
+END
+GO
+CREATE FN [login].[ReplicationFilter]() RETURNS string AS
+BEGIN
+  /* Replication filter ( login.ApiKey Schemas ) for the API key used for the current request, blank if none. */
+  DECLARE key string SET key = web.Bearer()
+  DECLARE n int SET n = LEN( key ) - 65
+  IF n < 1 RETURN ''
+  SET result = Schemas FROM login.ApiKey WHERE Id = PARSEINT( SUBSTRING( key, 1, n ) )
+END
+GO
+CREATE FN [login].[CheckCode]( u int, code string ) RETURNS bool AS
+BEGIN
+  /* Check a two-factor authenticator code ( 6 digits ) or a recovery code for user u */
+
+  -- After 5 failed codes, codes are refused for 15 minutes.
+  DECLARE failures int, failtime int, now int
+  SET failures = TotpFailures, failtime = TotpFailTime FROM login.user WHERE Id = u
+  SET now = date.Ticks()
+  IF failures >= 5 AND now < failtime + 900 * 1000000 RETURN false
+
+  IF LEN( code ) = 6
+  BEGIN
+    DECLARE secret binary, last int
+    SET secret = TotpSecret, last = TotpStep FROM login.user WHERE Id = u
+
+    -- Allow one 30 second time step either side for clock drift, each step can only be used once.
+    DECLARE c int SET c = PARSEINT( code )
+    DECLARE step int SET step = GLOBAL(0) / 30000000
+    DECLARE s int SET s = step - 1
+    WHILE s <= step + 1
+    BEGIN
+      IF s > last AND TOTP( secret, s ) = c
+      BEGIN
+        UPDATE login.user SET TotpStep = s, TotpFailures = 0 WHERE Id = u
+        RETURN true
+      END
+      SET s = s + 1
+    END
+  END
+  ELSE IF code != ''
+  BEGIN
+    DECLARE h string SET h = '' | login.hash( code )
+    DECLARE r int
+    FOR r = Id FROM login.Recovery WHERE User = u AND '' | HashedCode = h
+    BEGIN
+      DELETE FROM login.Recovery WHERE Id = r
+      UPDATE login.user SET TotpFailures = 0 WHERE Id = u
+      RETURN true
+    END
+  END
+  UPDATE login.user SET TotpFailures = CASE WHEN failures >= 5 THEN 1 ELSE failures + 1 END, TotpFailTime = now WHERE Id = u
+  RETURN false
+END
+GO
+CREATE FN [login].[ResetEmail]( r int, token string, address string ) AS
+BEGIN
+  /* 
+     Queues the password reset email, using the PasswordReset email.Template.
+     Edit the site address below, and the sender address and SmtpAccount in the template, before using password reset.
+  */
+  DECLARE site string SET site = 'http://localhost:3000'
+
+  DECLARE link string SET link = site | '/ResetPassword?r=' | r | '&t=' | token
+  DECLARE m int SET m = email.SendTemplate( 'PasswordReset', address, 'link=' | web.UrlEncode( link ) )
+END
+GO
+CREATE FN [login].[SetSession]( u int, hpw binary ) AS
+BEGIN
+  EXEC web.SetCookie( 'uid', '' | u, '' )
+  EXEC web.SetCookie( 'hpw', '' | hpw, '' )
+
+  /* Users with two-factor authentication also need a session key, as hpw can be computed from the password */
+  DECLARE required bool SET required = TotpRequired FROM login.user WHERE Id = u
+  IF required
+  BEGIN
+    DECLARE key binary SET key = RANDOMBYTES(16)
+    UPDATE login.user SET TotpSession = key WHERE Id = u
+    EXEC web.SetCookie( 'tfa', '' | key, '' )
+  END
+END
 GO
 CREATE FN [login].[get]( role int ) RETURNS int AS
 BEGIN
@@ -2269,27 +3077,83 @@ BEGIN
     DECLARE password string SET password = web.Form('password')
     SET result = Id FROM login.user WHERE Name = username
     DECLARE hpw binary SET hpw = login.hash( password|result )
-    SET result = Id FROM login.user WHERE Id = result AND HashedPassword = hpw
+    DECLARE required bool
+    SET result = Id, required = TotpRequired FROM login.user WHERE Id = result AND HashedPassword = hpw
     IF result > 0
     BEGIN
-      EXEC web.SetCookie( 'uid', '' | result, '' )
-      EXEC web.SetCookie( 'hpw', '' | hpw, '' )
+      IF required
+      BEGIN
+        /* 
+           Second step, ask for authenticator code. The pending login is kept in login.Pending, the form only has a random token.
+           Refused for a read-only request, as login.Pending ( and failed codes ) would not be saved.
+        */
+        IF READONLY() = 1
+        BEGIN
+          EXEC web.Head( 'Login' )
+          SELECT '<p>Two-factor login is not allowed for a read-only request.'
+          EXEC web.Trailer()
+          RETURN 0
+        END
+        DECLARE now int SET now = date.Ticks()
+        DELETE FROM login.Pending WHERE Expires < now
+        DECLARE token string SET token = '' | RANDOMBYTES(16)
+        INSERT INTO login.Pending( User, HashedToken, Expires ) 
+        VALUES ( result, login.hash( token ), now + 300 * 1000000 ) -- Expires after five minutes.
+        EXEC web.Head( 'Login' )
+        SELECT '<form method=post><input type=hidden name=tp value=' | LASTID() | '><input type=hidden name=tt value=' | token | '>'
+          | 'Authenticator code ( or recovery code ) <input name=code autocomplete=off><br><input type=submit value=Login></form>'
+        EXEC web.Trailer()
+        RETURN 0
+      END
+      EXEC login.SetSession( result, hpw )
       RETURN result
     END
   END
 
+  /* Authenticator code for a pending login, only checked if the request is not read-only ( see above ). */
+  DECLARE tp string SET tp = web.Form('tp')
+  IF tp != '' AND READONLY() = 0
+  BEGIN
+    DECLARE p int SET p = PARSEINT(tp)
+    DECLARE pu int, ptok binary, pexpires int
+    SET pu = User, ptok = HashedToken, pexpires = Expires FROM login.Pending WHERE Id = p
+    IF pu > 0 AND date.Ticks() <= pexpires AND '' | ptok = '' | login.hash( web.Form('tt') )
+    BEGIN
+      IF login.CheckCode( pu, web.Form('code') )
+      BEGIN
+        DELETE FROM login.Pending WHERE Id = p
+        DECLARE hpwu binary SET hpwu = HashedPassword FROM login.user WHERE Id = pu
+        EXEC login.SetSession( pu, hpwu )
+        RETURN pu
+      END
+    END
+  END
+
+  /* The bearer token is not logged, so the API key user is recorded for a replica applying the transaction ( see RECORDED ). */
+  DECLARE bearer string SET bearer = web.Bearer()
+  SET result = RECORDED( CASE WHEN bearer = '' THEN 0 ELSE login.ApiKeyUser( bearer ) END )
+  IF result > 0 RETURN result
+  IF bearer != ''
+  BEGIN
+    DECLARE x int SET x = STATUSCODE( 401 )
+    SELECT 'Invalid API key'
+    RETURN 0
+  END
+
   DECLARE uids string SET uids = web.Cookie('uid')
   DECLARE hpwf string SET hpwf = web.Cookie('hpw')
 
   IF uids != ''
   BEGIN
     DECLARE uid int SET uid = PARSEINT(uids)
-    DECLARE hpwt binary SET hpwt = HashedPassword FROM login.user WHERE Id = uid
-    IF hpwf = '' | hpwt RETURN uid
+    DECLARE hpwt binary, tfa binary, tfarequired bool
+    SET hpwt = HashedPassword, tfarequired = TotpRequired, tfa = TotpSession FROM login.user WHERE Id = uid
+    IF hpwf = '' | hpwt AND ( NOT tfarequired OR web.Cookie('tfa') = '' | tfa ) RETURN uid
   END
 
   EXEC web.Head( 'Login' )
   SELECT '<form method=post>User Name <input name=username><br>Password <input type=password name=password><br><input type=submit value=Login></form>'
+  SELECT '<p><a href=/ForgotPassword>Forgot password</a>'
   EXEC web.Trailer()
 
   RETURN 0
@@ -2300,7 +3164,21 @@ BEGIN
   SET result = ARGON(s,'pomesoft saltiness')
 END
 GO
-INSERT INTO [login].[user](Id,[Name],[HashedPassword]) VALUES 
+INSERT INTO [login].[user](Id,[Name],[HashedPassword],[Email],[TotpSecret],[TotpRequired],[TotpStep],[TotpSession],[Role],[TotpFailures],[TotpFailTime]) VALUES 
+GO
+
+INSERT INTO [login].[Reset](Id,[User],[HashedToken],[Expires]) VALUES 
+GO
+
+INSERT INTO [login].[Recovery](Id,[User],[HashedCode]) VALUES 
+GO
+
+INSERT INTO [login].[ApiKey](Id,[User],[Name],[HashedSecret],[Scopes],[Expires],[Schemas]) VALUES 
+GO
+INSERT INTO [login].[Role](Id,[Name],[Statements],[RowLimit],[ReadOnly]) VALUES 
+GO
+
+INSERT INTO [login].[Pending](Id,[User],[HashedToken],[Expires]) VALUES 
 GO
 
 --############################################
@@ -2346,14 +3224,255 @@ BEGIN
 END
 GO
 INSERT INTO [timed].[Job](Id,[fn],[at]) VALUES 
+(1,'log.Prune',0)
+(2,'email.Retry',0)
 GO
 
 --############################################
 CREATE SCHEMA [log]
 CREATE TABLE [log].[Transaction]([data] binary) 
 GO
+CREATE TABLE [log].[Replica]([Name] string,[Ip] string,[LastSeen] int,[Applied] int,[Reseed] bool,[Verified] int,[VerifiedId] int,[Diverged] string) 
+GO
+CREATE TABLE [log].[Change]([Transaction] int,[Table] int,[Sql] string) 
+GO
+CREATE INDEX [ByTransaction] ON [log].[Change]([Transaction])
+GO
+CREATE FN [log].[Prune]() AS
+BEGIN
+  /* 
+     Delete transactions that every replica has applied. Called hourly as a timed job.
+     Edit the settings below as required.
+  */
+  DECLARE keep int SET keep = 1000 -- Minimum number of transactions kept.
+  DECLARE window int SET window = 7 * 24 * 3600 * 1000000 -- Replicas not seen for 7 days are not waited for.
+
+  DECLARE now int SET now = date.Ticks()
+
+  -- Replicas not seen within the window must be re-seeded.
+  UPDATE log.Replica SET Reseed = true WHERE LastSeen < now - window
+
+  DECLARE upto int, a int
+  SET upto = log.LastId() - keep
+  FOR a = Applied FROM log.Replica WHERE NOT Reseed
+  BEGIN
+    IF a < upto SET upto = a
+  END
+
+  DELETE FROM log.Transaction WHERE Id <= upto
+  DELETE FROM log.Change WHERE Transaction <= upto
+
+  UPDATE timed.Job SET at = now + 3600 * 1000000 WHERE fn = 'log.Prune'
+END
+GO
+CREATE FN [log].[ReplicaPoll]( k int ) RETURNS bool AS
+BEGIN
+  /* 
+     Called when a replica polls for transaction k, returns true ( with a 410 response ) if the transaction
+     has been deleted by log.Prune, or the replica is ahead of this server ( e.g. after a replica that was behind was promoted ), 
+     in which case the replica cannot continue.
+
+     Records the progress of the replica named by the name query parameter. This is not logged, so log.Replica is not replicated.
+     To limit updates, it is only recorded when the applied Id changes, or every 10 seconds. 
+  */
+  DECLARE id int
+  SET id = Id FROM log.Transaction WHERE Id = k
+  SET result = ( id != k AND k <= log.LastId() ) OR k > log.LastId() + 1
+
+  DECLARE name string SET name = web.Query('name')
+  IF name != ''
+  BEGIN
+    DECLARE rid int, applied int, seen int, reseed bool, now int, x int
+    SET now = date.Ticks()
+    SET rid = Id, applied = Applied, seen = LastSeen, reseed = Reseed FROM log.Replica WHERE Name = name
+    IF rid = 0 OR applied != k - 1 OR reseed != result OR now - seen > 10000000
+      SET x = REPLICASEEN( name, web.ClientIp(), k - 1, result )
+  END
+
+  IF result
+  BEGIN
+    DECLARE status int SET status = STATUSCODE( 410 )
+    IF k > log.LastId() + 1
+      SELECT 'Replica has applied transactions this server does not have, the replica must be re-seeded.'
+    ELSE
+      SELECT 'Transaction ' | k | ' has been deleted from log.Transaction, the replica must be re-seeded.'
+  END
+END
+GO
+CREATE FN [log].[ReplicaSeen]( name string, ip string, applied int, reseed bool ) AS
+BEGIN
+  /* Records the progress of a replica ( see REPLICASEEN and log.ReplicaPoll ). */
+  DECLARE rid int SET rid = Id FROM log.Replica WHERE Name = name
+  IF rid = 0
+    INSERT INTO log.Replica( Name, Ip, LastSeen, Applied, Reseed ) VALUES ( name, ip, date.Ticks(), applied, reseed )
+  ELSE
+    UPDATE log.Replica SET Ip = ip, LastSeen = date.Ticks(), Applied = applied, Reseed = reseed WHERE Id = rid
+END
+GO
+CREATE FN [log].[ReplicaVerified]( name string, k int, diverged string ) AS
+BEGIN
+  /* Records the result of a replica checking it matches this server at transaction k ( see REPLICAVERIFIED and /ReplicaVerified ). */
+  UPDATE log.Replica SET Verified = date.Ticks(), VerifiedId = k, Diverged = diverged WHERE Name = name
+END
+GO
+CREATE FN [log].[LastId]() RETURNS int AS
+BEGIN
+  /* Id of the last transaction logged */
+  SET result = IdGen - 1 FROM sys.Table WHERE Id = sys.TableId( 'log', 'Transaction' )
+END
+GO
+CREATE FN [log].[Allowed]( t int, filter string ) RETURNS bool AS
+BEGIN
+  /* 
+     Is table t replicated by filter ( a space separated list of schemas or schema.table, see login.ApiKey )?
+     The sys schema ( which has the table and function definitions ) is always replicated, log changes only delete old transactions.
+  */
+  DECLARE s int, sname string, tname string
+  SET s = Schema, tname = Name FROM sys.Table WHERE Id = t
+  SET sname = sys.SchemaName( s )
+  SET filter = ' ' | filter | ' '
+  RETURN sname = 'sys' OR sname = 'log' 
+    OR REPLACE( filter, ' ' | sname | ' ', '' ) != filter
+    OR REPLACE( filter, ' ' | sname | '.' | tname | ' ', '' ) != filter
+END
+GO
+CREATE FN [log].[FilteredRecord]( k int, filter string ) RETURNS binary AS
+BEGIN
+  /* Log record for transaction k with only the changes allowed by filter ( see log.Allowed ). */
+  DECLARE t int, sql string, changes string
+  FOR t = Table, sql = Sql FROM log.Change WHERE Transaction = k
+    IF log.Allowed( t, filter ) SET changes = changes | sql
+  RETURN LOGRECORD( changes )
+END
+GO
+CREATE FN [log].[ScriptFiltered]( filter string ) AS
+BEGIN
+  /* 
+     Script to initialise a filtered replica: all schemas, tables and functions, with data only for the tables allowed by filter ( see log.Allowed ).
+     Tables are created in Id order, so the Ids match the master provided no tables have been dropped.
+     Login and email functions are not included ( see log.ScriptedFunction ).
+  */
+  SELECT '
+CREATE SCHEMA ' | sys.QuoteName( Name ) | '
+GO' FROM sys.Schema WHERE Id > 1
+
+  DECLARE t int, s int, sname string
+  FOR t = Id FROM sys.Table WHERE Id > 6
+    EXEC sys.ScriptTable(t)
+
+  SELECT '
+CREATE FN ' | sys.Dot( sys.SchemaName(Schema), Name ) | Def | '
+GO' FROM sys.Function WHERE log.ScriptedFunction( Schema )
+
+  FOR t = Id, s = Schema FROM sys.Table WHERE Id > 6
+  BEGIN
+    SET sname = sys.SchemaName( s )
+    IF sname != 'log' AND sname != 'browse' AND log.Allowed( t, filter ) EXEC sys.ScriptData(t,1)
+  END
+
+  FOR s = Id FROM sys.Schema
+    EXEC sys.ScriptSchemaBrowse(s)
+
+  /* Replication continues from the next transaction. */
+  DECLARE k int SET k = log.LastId()
+  IF k > 0 SELECT '
+INSERT INTO log.Transaction(Id) VALUES (' | k | ')
+DELETE FROM log.Transaction WHERE Id = ' | k | '
+GO'
+END
+GO
+CREATE FN [log].[ScriptFunction]( f int ) AS
+BEGIN
+  /* Script function f, for log.Change. The function is created, or altered if it already exists. Login and email functions are not scripted. */
+  SELECT '
+DECLARE sid int, f int
+SET sid = Id FROM sys.Schema WHERE Name = ' | sys.SingleQuote( sys.SchemaName(Schema) ) | '
+SET f = Id FROM sys.Function WHERE Schema = sid AND Name = ' | sys.SingleQuote( Name ) | '
+IF f = 0 EXECUTE( ' | sys.SingleQuote( 'CREATE FN ' | sys.Dot( sys.SchemaName(Schema), Name ) | Def ) | ' )
+ELSE EXECUTE( ' | sys.SingleQuote( 'ALTER FN ' | sys.Dot( sys.SchemaName(Schema), Name ) | Def ) | ' )
+GO'
+  FROM sys.Function WHERE Id = f AND log.ScriptedFunction( Schema )
+END
+GO
+CREATE FN [log].[ScriptedFunction]( s int ) RETURNS bool AS
+BEGIN
+  /* Are functions in schema s sent to filtered replicas? Login and email functions are not, as they can contain secrets ( such as the salt in login.hash ). */
+  DECLARE sname string SET sname = sys.SchemaName( s )
+  RETURN sname != 'login' AND sname != 'email'
+END
+GO
+CREATE FN [log].[ScriptRows]( t int, first int, last int ) AS
+BEGIN
+  /* Script rows of table t with Id from first to last, replacing any existing rows, for log.Change. */
+  DECLARE tname string SET tname = sys.TableName(t)
+  DECLARE range string SET range = ' WHERE Id >= ' | first | ' AND Id <= ' | last
+  SELECT '
+DELETE FROM ' | tname | range | '
+INSERT INTO ' | tname | sys.ColNames(t) | ' VALUES 
+'
+  EXECUTE( 'SELECT ''(''|' | sys.ColValues(t) | '|'')
+'' FROM ' | tname | range )
+  SELECT 'GO
+'
+END
+GO
 INSERT INTO [log].[Transaction](Id,[data]) VALUES 
 GO
+INSERT INTO [log].[Replica](Id,[Name],[Ip],[LastSeen],[Applied],[Reseed],[Verified],[VerifiedId],[Diverged]) VALUES 
+GO
+INSERT INTO [log].[Change](Id,[Transaction],[Table],[Sql]) VALUES 
+GO
+
+--############################################
+CREATE SCHEMA [audit]
+CREATE TABLE [audit].[Event]([User] int,[Time] int,[Ip] string,[Path] string,[Table] int,[RowId] int,[Before] string,[After] string) 
+GO
+CREATE FN [audit].[Capture]( e int, after bool ) AS
+BEGIN
+  /* Record a snapshot of the row for audit Event e, in the Before or After column */
+  DECLARE t int, k int
+  SET t = Table, k = RowId FROM audit.Event WHERE Id = e
+  IF t = 0 OR k = 0 RETURN
+
+  EXECUTE( 'DECLARE v string SET v = ' | audit.RowText( t ) | ' FROM ' | sys.TableName( t ) | ' WHERE Id = ' | k
+    | ' UPDATE audit.Event SET ' | CASE WHEN after THEN 'After' ELSE 'Before' END | ' = v WHERE Id = ' | e )
+END
+GO
+CREATE FN [audit].[Finish]( e int, k int ) AS
+BEGIN
+  /* Record the row after the change, k is the Id of an inserted row ( or 0 ) */
+  IF k != 0 UPDATE audit.Event SET RowId = k WHERE Id = e
+  EXEC audit.Capture( e, true )
+END
+GO
+CREATE FN [audit].[RowText]( table int ) RETURNS string AS
+BEGIN
+  /* Expression for text of a row, used for Before and After snapshots. Binary values are shown as length only. */
+  DECLARE col string, typ int
+  SET result = '''Id='' | Id'
+  FOR col = sys.QuoteName(Name), typ = Type FROM sys.Column WHERE Table = table
+    SET result |= ' | '', ' | col | '='' | ' | CASE 
+      WHEN typ % 8 = 1 THEN '''binary('' | BINLEN(' | col | ') | '')'''
+      WHEN typ % 8 = 2 THEN 'sys.SingleQuote(' | col | ')'
+      ELSE col 
+    END
+END
+GO
+CREATE FN [audit].[Start]( u int, t int, k int ) RETURNS int AS
+BEGIN
+  /* Start recording a change to row k of table t by user u, returns Id of audit Event */
+  INSERT INTO audit.Event( User, Time, Ip, Path, Table, RowId ) 
+  VALUES ( u, date.Ticks(), web.ClientIp(), web.Path(), t, k )
+  SET result = LASTID()
+  EXEC audit.Capture( result, false )
+END
+GO
+INSERT INTO [audit].[Event](Id,[User],[Time],[Ip],[Path],[Table],[RowId],[Before],[After]) VALUES 
+GO
+CREATE TABLE [audit].[SqlHistory]([User] int,[Time] int,[Sql] string,[ReadOnly] bool,[Error] string,[Rows] int) 
+GO
+INSERT INTO [audit].[SqlHistory](Id,[User],[Time],[Sql],[ReadOnly],[Error],[Rows]) VALUES 
+GO
 
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'sys'
@@ -2439,7 +3558,7 @@ SET sid = Id FROM sys.Schema WHERE Name = 'email'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Delayed'
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
-VALUES (cid, 0,'','',12,'',0,'',0,0,'','')
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'time'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
 VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
@@ -2452,6 +3571,18 @@ VALUES (tid,'email.MsgName','email.MsgSelect','','','',0)
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'format'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
 VALUES (cid, 0,'','0 means body is plain text, 1 means HTML.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'text'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Plain text alternative for an HTML body, blank means it is generated from the HTML.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'status'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','0 means queued, 1 sending, 2 sent, 3 retrying, 4 failed.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'response'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Response ( or error ) for the last attempt, see email.Attempt for all attempts.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'headers'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Extra headers, one per line, e.g. List-Unsubscribe: <mailto:unsubscribe@example.com>',0,'',0,'',0,0,'','')
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'account'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
 VALUES (cid, 0,'','',16,'',0,'',0,0,'','')
@@ -2461,14 +3592,14 @@ SET sid = Id FROM sys.Schema WHERE Name = 'email'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Queue'
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
-VALUES (cid, 0,'','',12,'',0,'',0,0,'','')
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
 GO
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'email'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'SendError'
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
-VALUES (cid, 0,'','',12,'',0,'',0,0,'','')
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
 SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'time'
 INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
 VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
@@ -2478,6 +3609,100 @@ SET sid = Id FROM sys.Schema WHERE Name = 'email'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'SmtpAccount'
 INSERT INTO browse.Table(Id,NameFunction, SelectFunction, DefaultOrder, Title, Description, Role) 
 VALUES (tid,'email.SmtpAccountName','email.SmtpAccountSelect','','','',0)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'port'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','0 means the default port ( 587 for STARTTLS, 465 for TLS, 25 for none ).',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'security'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','0 means STARTTLS, 1 means TLS, 2 means none ( e.g. for a local relay or test server ).',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'mechanism'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Space separated list of PLAIN LOGIN XOAUTH2, blank means PLAIN. Not used if username is blank.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'timeout'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Timeout in seconds, 0 means the default ( 60 ).',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'server'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','SMTP server, or directory for file transport, or sendmail command ( blank means sendmail ).',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'transport'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','0 means SMTP, 1 means write .eml files to the server directory, 2 means sendmail.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'connections'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Maximum connections ( and emails sent at the same time ), 0 means 4, at most 100.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'ratelimit'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Maximum emails sent per minute, 0 means no limit.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'retrybase'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Seconds before the first retry of a temporary send error, doubled after each attempt, 0 means 600.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'retrycap'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Maximum seconds between retries, 0 means one day.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'maxattempts'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','After this many attempts the email fails ( and is logged in email.SendError ), 0 means no limit.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'dkimdomain'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Domain for DKIM signing, blank means emails are not signed. See the DKIM Menu link.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'dkimkey'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','RSA private key ( PEM ) for DKIM signing.',0,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'email'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Attachment'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'filename'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Blank means the Path of the web.File ( after the last / ).',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'contenttype'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Blank means the ContentType of the web.File, or application/octet-stream.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'file'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','If not 0, the content is taken from this web.File.',7,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'email'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Recipient'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'kind'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','0 means To, 1 means Cc, 2 means Bcc.',0,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'email'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Attempt'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'msg'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',13,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'time'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'status'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','2 means sent, 3 retrying, 4 failed.',0,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'email'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Template'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'name'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Name passed to email.SendTemplate.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'html'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','{{name}} is replaced by the parameter value ( HTML-escaped ), {{{name}}} by the value without escaping. Blank for a plain text email.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'text'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Plain text version, generated from the html if blank.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'account'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',16,'',0,'',0,0,'','')
 GO
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'rtest'
@@ -2486,6 +3711,38 @@ GO
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'login'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'user'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Role'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Restricts use of the SQL console, 0 means unrestricted.',26,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Role'
+INSERT INTO browse.Table(Id,NameFunction, SelectFunction, DefaultOrder, Title, Description, Role) 
+VALUES (tid,'login.RoleName','login.RoleSelect','','','Restrictions for the SQL console.',0)
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Statements'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Space separated list of allowed statement types ( e.g. SELECT EXEC ), or * for all.',0,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'RowLimit'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','Maximum rows output, 0 means the default ( 1000 ).',0,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Reset'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',22,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Expires'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'login'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Recovery'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',22,'',0,'',0,0,'','')
 GO
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'timed'
@@ -2497,4 +3754,34 @@ GO
 DECLARE tid int, sid int, cid int
 SET sid = Id FROM sys.Schema WHERE Name = 'log'
 SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Transaction'
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'log'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Replica'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'LastSeen'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'audit'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'Event'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',22,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Time'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Table'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',2,'',0,'',0,0,'','')
+GO
+DECLARE tid int, sid int, cid int
+SET sid = Id FROM sys.Schema WHERE Name = 'audit'
+SET tid = Id FROM sys.Table WHERE Schema = sid AND Name = 'SqlHistory'
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'User'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',22,'',0,'',0,0,'','')
+SET cid=Id FROM sys.Column WHERE Table = tid AND Name = 'Time'
+INSERT INTO browse.Column(Id,[Position],[Label],[Description],[RefersTo],[Default],[InputCols],[InputFunction],[InputRows],[Style],[DisplayFunction],[ParseFunction]) 
+VALUES (cid, 0,'','',0,'',0,'',0,0,'date.MicroSecToString','')
 GO";
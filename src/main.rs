@@ -13,14 +13,26 @@ async fn main() {
 
     let listen = format!("{}:{}", args.ip, args.port);
     let listen = listen.parse().expect("Error parsing listen address:port");
-    let is_master = args.rep == "";
+    let is_master = args.rep.is_empty();
     let replicate_source = args.rep;
     let replicate_credentials = args.login;
+    let replicate_name = if args.name.is_empty() {
+        args.port.to_string()
+    } else {
+        args.name
+    };
 
-    // Construct an AtomicFile. This ensures that updates to the database are "all or nothing".
-    let file = Box::new(SimpleFileStorage::new("rustweb.rustdb"));
-    let upd = Box::new(SimpleFileStorage::new("rustweb.upd"));
-    let stg = Box::new(AtomicFile::new(file, upd));
+    // API key for replication, read from file or RUSTWEB_API_KEY environment variable.
+    let replicate_key = if args.keyfile.is_empty() {
+        std::env::var("RUSTWEB_API_KEY").unwrap_or_default()
+    } else {
+        std::fs::read_to_string(&args.keyfile)
+            .expect("Error reading keyfile")
+            .trim()
+            .to_string()
+    };
+
+    let stg = open_storage();
 
     // SharedPagedData allows for one writer and multiple readers.
     // Note that readers never have to wait, they get a "virtual" read-only copy of the database.
@@ -31,22 +43,70 @@ async fn main() {
         s.trace = args.tracemem;
     }
     // Construct map of "builtin" functions that can be called in SQL code.
-    // Include extra functions ARGON, EMAILTX and SLEEP as well as the standard functions.
+    // Include extra functions ARGON, EMAILTX, SLEEP, RANDOMBYTES, TOTP, RENDER, BINPACK etc. as well as the standard functions.
     let mut bmap = BuiltinMap::default();
     standard_builtins(&mut bmap);
     let list = [
         ("ARGON", DataKind::Binary, CompileFunc::Value(c_argon)),
+        ("SHAHASH", DataKind::Binary, CompileFunc::Value(c_sha_hash)),
         ("EMAILTX", DataKind::Int, CompileFunc::Int(c_email_tx)),
         ("SLEEP", DataKind::Int, CompileFunc::Int(c_sleep)),
         ("TRANSWAIT", DataKind::Int, CompileFunc::Int(c_trans_wait)),
-        /*
-                ("BINPACK", DataKind::Binary, CompileFunc::Value(c_binpack)),
-                (
-                    "BINUNPACK",
-                    DataKind::Binary,
-                    CompileFunc::Value(c_binunpack),
-                ),
-        */
+        (
+            "RANDOMBYTES",
+            DataKind::Binary,
+            CompileFunc::Value(c_random_bytes),
+        ),
+        ("TOTP", DataKind::Int, CompileFunc::Int(c_totp)),
+        ("READONLY", DataKind::Int, CompileFunc::Int(c_readonly)),
+        ("TOTPKEY", DataKind::String, CompileFunc::Value(c_totp_key)),
+        ("QRSVG", DataKind::String, CompileFunc::Value(c_qr_svg)),
+        ("BEARER", DataKind::String, CompileFunc::Value(c_bearer)),
+        ("RECORDED", DataKind::Int, CompileFunc::Int(c_recorded)),
+        ("DKIMKEY", DataKind::Int, CompileFunc::Int(c_dkim_key)),
+        (
+            "DKIMRECORD",
+            DataKind::String,
+            CompileFunc::Value(c_dkim_record),
+        ),
+        ("RENDER", DataKind::String, CompileFunc::Value(c_render)),
+        (
+            "SQLVERBS",
+            DataKind::String,
+            CompileFunc::Value(c_sql_verbs),
+        ),
+        ("ROWLIMIT", DataKind::Int, CompileFunc::Int(c_row_limit)),
+        ("SQLHISTORY", DataKind::Int, CompileFunc::Int(c_sql_history)),
+        (
+            "REPLICASEEN",
+            DataKind::Int,
+            CompileFunc::Int(c_replica_seen),
+        ),
+        (
+            "REPLICAVERIFIED",
+            DataKind::Int,
+            CompileFunc::Int(c_replica_verified),
+        ),
+        ("SNAPSHOT", DataKind::Int, CompileFunc::Int(c_snapshot)),
+        ("PROMOTE", DataKind::Int, CompileFunc::Int(c_promote)),
+        (
+            "TABLECHECKSUM",
+            DataKind::String,
+            CompileFunc::Value(c_table_checksum),
+        ),
+        ("BINPACK", DataKind::Binary, CompileFunc::Value(c_binpack)),
+        (
+            "LOGRECORD",
+            DataKind::Binary,
+            CompileFunc::Value(c_log_record),
+        ),
+        (
+            "BINUNPACK",
+            DataKind::Binary,
+            CompileFunc::Value(c_binunpack),
+        ),
+        ("FRAMEADD", DataKind::Int, CompileFunc::Int(c_frame_add)),
+        ("FRAMES", DataKind::Binary, CompileFunc::Value(c_frames)),
     ];
     for (name, typ, cf) in list {
         bmap.insert(name.to_string(), (typ, cf));
@@ -68,56 +128,120 @@ async fn main() {
         email_tx,
         sleep_tx,
         wait_tx,
-        is_master,
+        is_master: AtomicBool::new(is_master),
         replicate_source,
         replicate_credentials,
+        replicate_key,
+        replicate_name,
+        verify: args.verify,
+        repair: args.repair,
         tracetime: args.tracetime,
+        snapshots: Mutex::new(BTreeMap::new()),
+        forward_client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap(),
     });
 
+    // Receivers for the email and sleep tasks, if they are not started ( until replica is promoted ).
+    let mut tasks = None;
+    // Script to initialise a new filtered replica ( see bootstrap ).
+    let mut initsql = String::new();
     if is_master {
-        // Start the email task.
-        let ssc = ss.clone();
-        tokio::spawn(async move { email_loop(email_rx, ssc).await });
-
-        // Start the sleep task.
-        let ssc = ss.clone();
-        tokio::spawn(async move { sleep_loop(sleep_rx, ssc).await });
+        start_tasks(&ss, email_rx, sleep_rx);
     } else {
+        tasks = Some((email_rx, sleep_rx));
+
         // Start the sync task.
         let ssc = ss.clone();
         tokio::spawn(async move { sync_loop(sync_rx, ssc).await });
+
+        // Get initial copy of master database if necessary.
+        if let Some(script) = bootstrap(ss.clone()).await {
+            initsql = script;
+        }
     }
 
     // Start the task that updates the database.
     let ssc = ss.clone();
+    let rt = tokio::runtime::Handle::current();
     thread::spawn(move || {
         let ss = ssc;
 
         // Get write-access to database ( there will only be one of these ).
         let wapd = AccessPagedData::new_writer(spd);
 
-        let db = Database::new(wapd, if is_master { init::INITSQL } else { "" }, bmap);
-        if !is_master {
+        let db = Database::new(wapd, if is_master { init::INITSQL } else { &initsql }, bmap);
+        if is_master {
+            // Check for emails already queued, now the database is initialised.
+            let _ = ss.email_tx.send(());
+        } else {
             let _ = sync_tx.send(db.is_new);
         }
+        // Result of replication_filtered, cleared when login.ApiKey may have changed.
+        let mut filtered = None;
         loop {
             let mut sm = rx.blocking_recv().unwrap();
-            sm.st.run(&db, ss.tracetime);
-
-            if sm.st.log && db.changed() {
-                if let Some(t) = db.get_table(&ObjRef::new("log", "Transaction")) {
-                    // Append serialised transaction to log.Transaction table
-                    let ser = bincode::serialize(&sm.st.x.qy).unwrap();
-                    let ser = Value::RcBinary(Rc::new(ser));
-                    let mut row = t.row();
-                    row.id = t.alloc_id() as i64;
-                    row.values[0] = ser;
-                    t.insert(&db, &mut row);
+            let batch = std::mem::take(&mut sm.st.batch);
+            if batch.is_empty() {
+                // Only transactions on the master are logged ( or the transaction that promotes a replica ).
+                let log = sm.st.log;
+                sm.st.log &= ss.is_master();
+                // Copy of the database before the transaction, used to find the changed rows for filtered replicas.
+                let pre = if log && *filtered.get_or_insert_with(|| replication_filtered(&db)) {
+                    Some(AccessPagedData::new_reader(ss.spd.clone()))
+                } else {
+                    None
+                };
+                sm.st.run(&db, ss.tracetime);
+                if let Some(ext) = sm.st.x.ext.downcast_ref::<TransExt>() {
+                    if ext.promote && !ss.is_master() {
+                        // Batches from the old master are not applied after this.
+                        ss.is_master.store(true, Ordering::SeqCst);
+                        sm.st.log = log;
+                        if let Some((email_rx, sleep_rx)) = tasks.take() {
+                            let _rt = rt.enter();
+                            start_tasks(&ss, email_rx, sleep_rx);
+                            let _ = ss.email_tx.send(());
+                        }
+                        println!("Replica promoted to master");
+                    }
+                }
+                if sm.st.log && db.changed() {
+                    let changes = pre.map(|pre| changed_rows(&db, &pre));
+                    let recorded = match sm.st.x.ext.downcast_mut::<TransExt>() {
+                        Some(ext) => std::mem::take(&mut ext.recorded),
+                        None => Vec::new(),
+                    };
+                    sm.st.tid = log_transaction(&db, log_record(&sm.st.x.qy, &recorded));
+                    if let Some(changes) = changes {
+                        record_changes(&db, sm.st.tid, changes);
+                    }
+                }
+            } else if ss.is_master() {
+                println!("Transactions from old master discarded ( replica has been promoted )");
+            } else {
+                // Batch of transactions from master, applied in one writer turn.
+                for (rec, qy, recorded) in batch {
+                    sm.st.x.qy = qy;
+                    if let Some(ext) = sm.st.x.ext.downcast_mut::<TransExt>() {
+                        ext.replay = recorded.into();
+                        ext.recorded.clear();
+                    }
+                    sm.st.run(&db, ss.tracetime);
+                    sm.st.x.rp.output.clear();
+                    log_transaction(&db, rec);
                 }
             }
+            if api_key_changed(&db) {
+                filtered = None;
+            }
             let updates = db.save();
             if updates > 0 {
-                let _ = ss.wait_tx.send(());
+                // Only logged transactions are waited for ( see TRANSWAIT ).
+                if sm.st.log {
+                    let _ = ss.wait_tx.send(());
+                }
                 println!("Pages updated={updates}");
             }
             let _x = sm.reply.send(sm.st);
@@ -130,16 +254,309 @@ async fn main() {
     let app = Router::new().route("/*key", get(h_get).post(h_post)).layer(
         ServiceBuilder::new()
             .layer(CookieManagerLayer::new())
-            .layer(Extension(ss.clone())),
+            .layer(Extension(ss.clone()))
+            .layer(middleware::from_fn(forward)),
     );
 
     // Run the axum app.
     axum::Server::bind(&listen)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
+/// Start the email and sleep tasks ( master only ). The writer thread signals the email task once the database is initialised.
+fn start_tasks(
+    ss: &Arc<SharedState>,
+    email_rx: mpsc::UnboundedReceiver<()>,
+    sleep_rx: mpsc::UnboundedReceiver<u64>,
+) {
+    // Start the email task.
+    let ssc = ss.clone();
+    tokio::spawn(async move { email_loop(email_rx, ssc).await });
+
+    // Start the sleep task.
+    let ssc = ss.clone();
+    tokio::spawn(async move { sleep_loop(sleep_rx, ssc).await });
+}
+
+/// Construct an AtomicFile for the database. This ensures that updates to the database are "all or nothing".
+fn open_storage() -> Box<AtomicFile> {
+    let file = Box::new(SimpleFileStorage::new("rustweb.rustdb"));
+    let upd = Box::new(SimpleFileStorage::new("rustweb.upd"));
+    Box::new(AtomicFile::new(file, upd))
+}
+
+/// Append log record to log.Transaction table, result is the Id of the record.
+fn log_transaction(db: &DB, rec: Vec<u8>) -> i64 {
+    if let Some(t) = db.get_table(&ObjRef::new("log", "Transaction")) {
+        let mut row = t.row();
+        row.id = t.alloc_id();
+        row.values[0] = Value::RcBinary(Rc::new(rec));
+        t.insert(db, &mut row);
+        row.id
+    } else {
+        0
+    }
+}
+
+/// Is there an API key with a replication filter ( login.ApiKey Schemas )? If so, changed rows are recorded in log.Change.
+fn replication_filtered(db: &DB) -> bool {
+    if let Some(t) = db.get_table(&ObjRef::new("login", "ApiKey")) {
+        if let Some(&col) = t.info.colmap.get("Schemas") {
+            for (pp, off) in t.scan(db) {
+                let p = &pp.borrow();
+                if !t.access(p, off).str(db, col).is_empty() {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Has login.ApiKey been changed ( or a table created or dropped ) since the database was last saved?
+fn api_key_changed(db: &DB) -> bool {
+    let changed = |t: &Table| !t.file.dirty_pages.borrow().is_empty();
+    changed(&db.sys_table)
+        || db
+            .get_table(&ObjRef::new("login", "ApiKey"))
+            .is_some_and(|t| changed(&t))
+}
+
+/// Rows changed by a transaction: table Id, table name, Ids of rows inserted or updated, Ids of rows deleted.
+type RowChanges = Vec<(i64, ObjRef, Vec<u64>, Vec<u64>)>;
+
+/// Find rows changed by the current transaction, by comparing the records in the changed pages of each table
+/// with the same pages in pre ( the database before the transaction ).
+fn changed_rows(db: &DB, pre: &AccessPagedData) -> RowChanges {
+    let mut result = Vec::new();
+    for t in db.tables.borrow().values() {
+        let (mut before, mut after) = (BTreeMap::new(), BTreeMap::new());
+        for pp in t.file.dirty_pages.borrow().iter() {
+            let p = &pp.borrow();
+            if p.level != 0 || p.pnum == u64::MAX {
+                continue;
+            }
+            page_rows(p, p.root, &mut after);
+            let data = pre.get_page(p.pnum);
+            if !data.is_empty() && data[0] == 0 {
+                let p = Page::new(t.file.rec_size, 0, data, p.pnum);
+                page_rows(&p, p.root, &mut before);
+            }
+        }
+        let upserts: Vec<u64> = after
+            .iter()
+            .filter(|(id, rec)| before.get(*id) != Some(*rec))
+            .map(|(id, _)| *id)
+            .collect();
+        let deletes: Vec<u64> = before
+            .keys()
+            .filter(|id| !after.contains_key(*id))
+            .copied()
+            .collect();
+        if !upserts.is_empty() || !deletes.is_empty() {
+            result.push((t.id, t.info.name.clone(), upserts, deletes));
+        }
+    }
+    result
+}
+
+/// Add the records of node x of page p and its children to map, keyed by Id.
+fn page_rows(p: &Page, x: usize, map: &mut BTreeMap<u64, Vec<u8>>) {
+    if x != 0 {
+        page_rows(p, p.left(x), map);
+        let off = p.rec_offset(x);
+        let rec = &p.data[off..off + p.rec_size()];
+        map.insert(rustdb::util::getu64(rec, 0), rec.to_vec());
+        page_rows(p, p.right(x), map);
+    }
+}
+
+/// Record the changes made by transaction tid in log.Change, as SQL scripts, for filtered replicas ( see log.FilteredRecord ).
+/// Changes to sys tables other than sys.Function are recorded with Table zero, as filtered replicas must then be re-seeded.
+fn record_changes(db: &DB, tid: i64, changes: RowChanges) {
+    let Some(ct) = db.get_table(&ObjRef::new("log", "Change")) else {
+        return;
+    };
+    // Rows are scripted by Id range, so bulk inserts and deletes give a short script.
+    let ranges = |ids: &[u64], table: i64| {
+        let mut sql = String::new();
+        let mut i = 0;
+        while i < ids.len() {
+            let first = ids[i];
+            while i + 1 < ids.len() && ids[i + 1] == ids[i] + 1 {
+                i += 1;
+            }
+            sql += &format!("EXEC log.ScriptRows({table},{first},{})\n", ids[i]);
+            i += 1;
+        }
+        sql
+    };
+    for (table, name, mut upserts, deletes) in changes {
+        let (mut table, sql) = match name.schema.as_str() {
+            "sys" if table == db.sys_function.id && deletes.is_empty() => (
+                table,
+                upserts
+                    .iter()
+                    .map(|f| format!("EXEC log.ScriptFunction({f})\n"))
+                    .collect(),
+            ),
+            "sys" => (0, String::new()),
+            "log" if name.name == "Transaction" && !deletes.is_empty() => {
+                (table, ranges(&deletes, table))
+            }
+            "log" => continue,
+            _ => {
+                upserts.extend(deletes);
+                upserts.sort_unstable();
+                (table, ranges(&upserts, table))
+            }
+        };
+        let mut script = String::new();
+        if !sql.is_empty() {
+            let mut tr = GenTransaction::new();
+            db.run(&sql, &mut tr);
+            if tr.rp.err.is_empty() {
+                script = String::from_utf8(tr.rp.output).unwrap();
+            } else {
+                // The transaction is still saved, but filtered replicas will have to be re-seeded.
+                println!("Error scripting changes to {} : {}", name.str(), tr.rp.err);
+                db.err.set(false);
+                table = 0;
+            }
+        }
+        let mut row = ct.row();
+        row.id = ct.alloc_id();
+        row.values[0] = Value::Int(tid);
+        row.values[1] = Value::Int(table);
+        row.values[2] = Value::String(Rc::new(script));
+        ct.insert(db, &mut row);
+    }
+}
+
+/// Start of each log record.
+const LOG_MAGIC: &[u8] = b"RWL";
+
+/// Log record format version. Format 1 records have no recorded builtin results, format 1 and 2 records have no rustdb version.
+const LOG_FORMAT: u8 = 3;
+
+/// Version of rustdb, saved in log records as it determines the query serialisation. Must match the version pinned in Cargo.toml.
+const RUSTDB_VERSION: &str = "3.0.0";
+
+/// Log record flag, payload is compressed.
+const LOG_DEFLATE: u8 = 1;
+
+/// Encode transaction as a log record.
+///
+/// The record is LOG_MAGIC, LOG_FORMAT, flags, CRC32 of payload ( 4 bytes, little-endian ),
+/// length of rustweb version, rustweb version, length of rustdb version, rustdb version ( RUSTDB_VERSION ),
+/// payload ( serialised query and recorded results of non-deterministic builtins
+/// such as RANDOMBYTES, compressed if that makes it smaller ).
+fn log_record(qy: &GenQuery, recorded: &[Vec<u8>]) -> Vec<u8> {
+    let ser = bincode::serialize(&(qy, recorded)).unwrap();
+    let mut flags = 0;
+    let mut payload = ser;
+    if payload.len() > 256 {
+        let mut comp = flate3::Compressor::new();
+        let cb = comp.deflate(&payload);
+        if cb.len() < payload.len() {
+            payload = cb;
+            flags |= LOG_DEFLATE;
+        }
+    }
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let db_version = RUSTDB_VERSION.as_bytes();
+    let mut rec = Vec::with_capacity(payload.len() + version.len() + db_version.len() + 11);
+    rec.extend_from_slice(LOG_MAGIC);
+    rec.push(LOG_FORMAT);
+    rec.push(flags);
+    rec.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    rec.push(version.len() as u8);
+    rec.extend_from_slice(version);
+    rec.push(db_version.len() as u8);
+    rec.extend_from_slice(db_version);
+    rec.extend_from_slice(&payload);
+    rec
+}
+
+/// Decode log record, checking format, rustweb and rustdb versions and CRC. Result is the query and recorded builtin results ( see log_record ).
+/// Records written before LOG_MAGIC was introduced are just the serialised query.
+fn read_log_record(rec: &[u8]) -> Result<(GenQuery, Vec<Vec<u8>>), String> {
+    if !rec.starts_with(LOG_MAGIC) {
+        return bincode::deserialize(rec)
+            .map(|qy| (qy, Vec::new()))
+            .map_err(|e| format!("unreadable record: {e}"));
+    }
+    if rec.len() < 10 {
+        return Err("record is truncated".to_string());
+    }
+    let format = rec[3];
+    if !(1..=LOG_FORMAT).contains(&format) {
+        return Err(format!(
+            "record format {format} is not supported ( expected {LOG_FORMAT} )"
+        ));
+    }
+    let flags = rec[4];
+    let crc = u32::from_le_bytes(rec[5..9].try_into().unwrap());
+    let vlen = rec[9] as usize;
+    if rec.len() < 10 + vlen {
+        return Err("record is truncated".to_string());
+    }
+    let version = String::from_utf8_lossy(&rec[10..10 + vlen]);
+    let ours = env!("CARGO_PKG_VERSION");
+    if major_minor(&version) != major_minor(ours) {
+        return Err(format!(
+            "record was written by rustweb {version}, which is incompatible with rustweb {ours}"
+        ));
+    }
+    let mut start = 10 + vlen;
+    if format >= 3 {
+        // The rustdb version determines the query serialisation.
+        let dlen = *rec.get(start).ok_or("record is truncated")? as usize;
+        let Some(db_version) = rec.get(start + 1..start + 1 + dlen) else {
+            return Err("record is truncated".to_string());
+        };
+        let db_version = String::from_utf8_lossy(db_version);
+        let db_ours = RUSTDB_VERSION;
+        if major_minor(&db_version) != major_minor(db_ours) {
+            return Err(format!(
+                "record was written with rustdb {db_version}, which is incompatible with rustdb {db_ours}"
+            ));
+        }
+        start += 1 + dlen;
+    }
+    let payload = &rec[start..];
+    if crc32fast::hash(payload) != crc {
+        return Err("record CRC check failed".to_string());
+    }
+    let ser = if flags & LOG_DEFLATE != 0 {
+        inflate(payload)?
+    } else {
+        payload.to_vec()
+    };
+    let result = if format == 1 {
+        bincode::deserialize(&ser).map(|qy| (qy, Vec::new()))
+    } else {
+        bincode::deserialize(&ser)
+    };
+    result.map_err(|e| format!("unreadable record: {e}"))
+}
+
+/// Decompress data, with an error if it is not valid ( flate3::inflate panics ).
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    std::panic::catch_unwind(|| flate3::inflate(data))
+        .map_err(|_| "data could not be decompressed".to_string())
+}
+
+/// Major and minor parts of version string, e.g. "0.13" for "0.13.0".
+fn major_minor(version: &str) -> &str {
+    match version.match_indices('.').nth(1) {
+        Some((i, _)) => &version[..i],
+        None => version,
+    }
+}
+
 /// Database initialisation string.
 mod init;
 
@@ -150,16 +567,28 @@ use mimalloc::MiMalloc;
 static MEMALLOC: MiMalloc = MiMalloc;
 
 use axum::{
-    extract::{Extension, Form, Multipart, Path, Query},
+    extract::{ConnectInfo, Extension, Form, Multipart, Path, Query},
+    http::{header::AUTHORIZATION, HeaderMap},
     routing::get,
     Router,
 };
 use rustdb::{
-    c_int, c_value, check_types, standard_builtins, AccessPagedData, AtomicFile, Block, BuiltinMap,
-    CExp, CExpPtr, CompileFunc, DataKind, Database, EvalEnv, Expr, GenTransaction, ObjRef, Part,
-    SharedPagedData, SimpleFileStorage, Transaction, Value, DB,
+    c_bool, c_int, c_value, check_types, standard_builtins, AccessPagedData, AtomicFile, Block,
+    BuiltinMap, CExp, CExpPtr, CompileFunc, DataKind, Database, EvalEnv, Expr, GenTransaction,
+    ObjRef, Part, SharedPagedData, SimpleFileStorage, Transaction, Value, DB,
+};
+use rustdb::{compact::CompactFile, gentrans::GenQuery, page::Page, table::Table};
+use std::{
+    any::Any,
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
 };
-use std::{collections::BTreeMap, rc::Rc, sync::Arc, thread};
 
 use tokio::sync::{broadcast, mpsc, oneshot};
 use tower::ServiceBuilder;
@@ -170,6 +599,10 @@ struct ServerTrans {
     x: Box<GenTransaction>,
     log: bool,
     readonly: bool,
+    /// Log records ( with decoded query ) from master to be applied ( replica only ).
+    batch: Vec<(Vec<u8>, GenQuery, Vec<Vec<u8>>)>,
+    /// Id of log record for the transaction ( zero if not logged ).
+    tid: i64,
 }
 
 impl ServerTrans {
@@ -178,21 +611,89 @@ impl ServerTrans {
             x: Box::new(GenTransaction::new()),
             log: true,
             readonly: false,
+            batch: Vec::new(),
+            tid: 0,
         };
         result.x.ext = TransExt::new();
         result
     }
 
+    /// Save the bearer token from the Authorization header ( if any ), see BEARER.
+    fn set_bearer(&mut self, headers: &HeaderMap) {
+        if let Some(auth) = headers.get(AUTHORIZATION) {
+            if let Some(token) = auth.to_str().ok().and_then(|s| s.strip_prefix("Bearer ")) {
+                if let Some(ext) = self.x.ext.downcast_mut::<TransExt>() {
+                    ext.bearer = token.trim().to_string();
+                }
+            }
+        }
+    }
+
     fn run(&mut self, db: &DB, tt: bool) {
         let sql = self.x.qy.sql.clone();
         if tt {
             let start = std::time::SystemTime::now();
-            db.run(&sql, &mut *self.x);
+            db.run(&sql, self);
             let time = start.elapsed().unwrap();
             println!("ran path={} time={}µs", self.x.arg(0, ""), time.as_micros());
         } else {
-            db.run(&sql, &mut *self.x);
+            db.run(&sql, self);
+        }
+    }
+}
+
+/// ServerTrans implements Transaction so that selected rows can be limited ( see ROWLIMIT ).
+impl Transaction for ServerTrans {
+    fn status_code(&mut self, code: i64) {
+        self.x.status_code(code)
+    }
+
+    fn header(&mut self, name: &str, value: &str) {
+        self.x.header(name, value)
+    }
+
+    fn selected(&mut self, values: &[Value]) {
+        if let Some(ext) = self.x.ext.downcast_mut::<TransExt>() {
+            if ext.row_limit > 0 {
+                ext.rows += 1;
+                if ext.rows > ext.row_limit {
+                    return;
+                }
+            }
         }
+        self.x.selected(values)
+    }
+
+    fn global(&self, kind: i64) -> i64 {
+        self.x.global(kind)
+    }
+
+    fn arg(&mut self, kind: i64, name: &str) -> Rc<String> {
+        self.x.arg(kind, name)
+    }
+
+    fn file_attr(&mut self, fnum: i64, atx: i64) -> Rc<String> {
+        self.x.file_attr(fnum, atx)
+    }
+
+    fn file_content(&mut self, fnum: i64) -> Arc<Vec<u8>> {
+        self.x.file_content(fnum)
+    }
+
+    fn set_error(&mut self, err: String) {
+        self.x.set_error(err)
+    }
+
+    fn get_error(&mut self) -> String {
+        self.x.get_error()
+    }
+
+    fn set_extension(&mut self, ext: Box<dyn Any + Send + Sync>) {
+        self.x.set_extension(ext)
+    }
+
+    fn get_extension(&mut self) -> Box<dyn Any + Send + Sync> {
+        self.x.get_extension()
     }
 }
 
@@ -211,6 +712,34 @@ struct TransExt {
     sleep: u64,
     /// Signals wait for new transaction to be logged
     trans_wait: bool,
+    /// Limit on number of rows selected ( zero means no limit ).
+    row_limit: u64,
+    /// Number of rows selected since limit was set.
+    rows: u64,
+    /// Console SQL to be recorded in audit.SqlHistory as a separate transaction ( see SQLHISTORY ).
+    history: Option<SqlHistory>,
+    /// Console SQL is being run, replica status is not recorded after this is set.
+    console: bool,
+    /// SQL recording replica status, run as a separate transaction that is not logged ( see REPLICASEEN ).
+    replica_status: String,
+    /// Snapshot id and start page requested.
+    snapshot: Option<(u64, u64)>,
+    /// Server is a replica ( set before the transaction is run ).
+    replica: bool,
+    /// Transaction is read-only, so updates are not saved ( set before the transaction is run ).
+    readonly: bool,
+    /// Signals replica is to be promoted to master.
+    promote: bool,
+    /// New DKIM key to be generated and saved as a separate transaction ( see DKIMKEY ).
+    dkim_key: Option<DkimKeyRequest>,
+    /// Frames added by FRAMEADD, each prefixed by its length.
+    frames: Vec<u8>,
+    /// Bearer token from the Authorization header ( see BEARER ), not saved in the log record.
+    bearer: String,
+    /// Results of non-deterministic builtins ( see replayable ), saved in the log record.
+    recorded: Vec<Vec<u8>>,
+    /// Recorded results to be replayed, when a replica applies a transaction from the master.
+    replay: VecDeque<Vec<u8>>,
 }
 
 impl TransExt {
@@ -219,6 +748,29 @@ impl TransExt {
     }
 }
 
+/// Console SQL recorded in audit.SqlHistory ( see SQLHISTORY ).
+struct SqlHistory {
+    user: i64,
+    time: i64,
+    sql: String,
+    readonly: bool,
+    error: String,
+    rows: i64,
+}
+
+/// Request for a new DKIM key for an email.SmtpAccount, with the user, path and client ip address for the audit trail.
+struct DkimKeyRequest {
+    account: i64,
+    user: i64,
+    path: String,
+    ip: String,
+}
+
+/// SQL string literal.
+fn sql_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
 /// State shared with handlers.
 struct SharedState {
     /// Shared storage used for read-only queries.
@@ -233,33 +785,83 @@ struct SharedState {
     sleep_tx: mpsc::UnboundedSender<u64>,
     /// For notifying tasks waiting for transaction.
     wait_tx: broadcast::Sender<()>,
-    /// Server is master ( not replicating another database ).
-    is_master: bool,
+    /// Server is master ( not replicating another database, or replica has been promoted ).
+    is_master: AtomicBool,
     replicate_source: String,
     replicate_credentials: String,
+    replicate_key: String,
+    /// Name reported to master when replicating.
+    replicate_name: String,
+    /// Interval in minutes for verifying replica matches master ( zero for never ).
+    verify: u64,
+    /// Re-fetch tables that do not match when verifying.
+    repair: bool,
     tracetime: bool,
+    /// Snapshots being downloaded by new replicas ( see SNAPSHOT ).
+    /// The time each snapshot was last used is kept, so expired snapshots can be removed.
+    snapshots: Mutex<BTreeMap<u64, (std::sync::mpsc::Sender<SnapshotRequest>, std::time::Instant)>>,
+    /// Client for forwarding updates from replica to master.
+    forward_client: reqwest::Client,
 }
 
 impl SharedState {
+    fn is_master(&self) -> bool {
+        self.is_master.load(Ordering::SeqCst)
+    }
+
+    /// Id of the next log.Transaction record.
+    fn next_tid(&self) -> i64 {
+        let apd = AccessPagedData::new_reader(self.spd.clone());
+        let db = Database::new(apd, "", self.bmap.clone());
+        let lt = db.table("log", "Transaction");
+        lt.id_gen.get()
+    }
+
+    /// Wait ( for up to 10 seconds ) until transaction tid from master has been applied.
+    async fn wait_applied(&self, tid: i64) {
+        let mut wait_rx = self.wait_tx.subscribe();
+        let timeout = tokio::time::sleep(core::time::Duration::from_secs(10));
+        tokio::pin!(timeout);
+        while self.next_tid() <= tid {
+            tokio::select! {
+               _ = wait_rx.recv() => {}
+               _ = &mut timeout => { break; }
+            }
+        }
+    }
+
     async fn process(&self, mut st: ServerTrans) -> ServerTrans {
+        if let Some(ext) = st.x.ext.downcast_mut::<TransExt>() {
+            ext.replica = !self.is_master();
+            ext.readonly = st.readonly;
+        }
         if st.readonly {
             let spd = self.spd.clone();
             let bmap = self.bmap.clone();
             let tracetime = self.tracetime;
             // Readonly request, use read-only copy of database.
-            tokio::task::spawn_blocking(move || {
+            let mut st = tokio::task::spawn_blocking(move || {
                 let apd = AccessPagedData::new_reader(spd);
                 let db = Database::new(apd, "", bmap);
                 st.run(&db, tracetime);
                 st
             })
             .await
-            .unwrap()
+            .unwrap();
+            if self.is_master() {
+                // Readonly request may still have deferred an update ( e.g. recording SQL history ).
+                let ext = st.x.get_extension();
+                if let Some(ext) = ext.downcast_ref::<TransExt>() {
+                    self.run_deferred(ext).await;
+                }
+                st.x.set_extension(ext);
+            }
+            st
         } else {
             let (reply, rx) = oneshot::channel::<ServerTrans>();
             let _err = self.tx.send(ServerMessage { st, reply }).await;
             let mut st = rx.await.unwrap();
-            if self.is_master {
+            if self.is_master() {
                 // Check if email needs sending or sleep time has been specified, etc.
                 let ext = st.x.get_extension();
                 if let Some(ext) = ext.downcast_ref::<TransExt>() {
@@ -269,12 +871,93 @@ impl SharedState {
                     if ext.tx_email {
                         let _ = self.email_tx.send(());
                     }
+                    self.run_deferred(ext).await;
                 }
             }
             st
         }
     }
 
+    /// Get chunk of snapshot pages starting at page start. Id zero starts a new snapshot.
+    /// Result is empty if the snapshot does not exist ( e.g. it expired ), or there are already SNAPSHOT_MAX snapshots.
+    async fn snapshot_chunk(&self, mut id: u64, start: u64) -> Vec<u8> {
+        let (reply, rx) = oneshot::channel::<Vec<u8>>();
+        {
+            let mut snapshots = self.snapshots.lock().unwrap();
+            if id == 0 {
+                snapshots.retain(|_, (_, used)| used.elapsed().as_secs() < SNAPSHOT_EXPIRE);
+                if snapshots.len() >= SNAPSHOT_MAX {
+                    println!("Snapshot refused, {SNAPSHOT_MAX} snapshots are already in use");
+                    return Vec::new();
+                }
+                let mut b = [0; 8];
+                getrandom::getrandom(&mut b).unwrap();
+                id = (u64::from_le_bytes(b) >> 2) | 1; // Positive as SQL int.
+                let (tx, rx) = std::sync::mpsc::channel::<SnapshotRequest>();
+                let (spd, bmap) = (self.spd.clone(), self.bmap.clone());
+                thread::spawn(move || snapshot_task(spd, bmap, id, rx));
+                snapshots.insert(id, (tx, std::time::Instant::now()));
+            }
+            match snapshots.get_mut(&id) {
+                Some((tx, used)) => {
+                    *used = std::time::Instant::now();
+                    if tx.send((start, reply)).is_err() {
+                        snapshots.remove(&id);
+                        return Vec::new();
+                    }
+                }
+                None => return Vec::new(),
+            }
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    /// Record console SQL history ( see SQLHISTORY ) and replica status ( see REPLICASEEN ), and save a new DKIM key ( see DKIMKEY ),
+    /// as separate transactions.
+    async fn run_deferred(&self, ext: &TransExt) {
+        if let Some(h) = &ext.history {
+            let sql = format!(
+                "INSERT INTO audit.SqlHistory( User, Time, Sql, ReadOnly, Error, Rows ) VALUES ({},{},{},{},{},{})",
+                h.user,
+                h.time,
+                sql_quote(&h.sql),
+                h.readonly,
+                sql_quote(&h.error),
+                h.rows
+            );
+            self.send_deferred(sql, true).await;
+        }
+        if !ext.replica_status.is_empty() {
+            self.send_deferred(ext.replica_status.clone(), false).await;
+        }
+        if let Some(k) = &ext.dkim_key {
+            // Generating the key takes a while, so it is not done in the writer thread.
+            let pem = tokio::task::spawn_blocking(dkim_key).await.unwrap();
+            let mut st = ServerTrans::new();
+            st.x.qy.sql = Arc::new(format!(
+                "EXEC email.SetDkimKey({},{},{})",
+                k.account,
+                k.user,
+                sql_quote(&pem)
+            ));
+            st.x.qy.path = k.path.clone();
+            st.x.qy.cookies.insert("ip".to_string(), k.ip.clone());
+            // Wait for the key to be saved, so it is shown when the page is reloaded.
+            let (reply, rx) = oneshot::channel::<ServerTrans>();
+            let _err = self.tx.send(ServerMessage { st, reply }).await;
+            let _ = rx.await;
+        }
+    }
+
+    /// Send sql to be run as a new transaction, without waiting for it to complete.
+    async fn send_deferred(&self, sql: String, log: bool) {
+        let mut st = ServerTrans::new();
+        st.log = log;
+        st.x.qy.sql = Arc::new(sql);
+        let (reply, _rx) = oneshot::channel::<ServerTrans>();
+        let _err = self.tx.send(ServerMessage { st, reply }).await;
+    }
+
     fn trim_cache(&self) {
         self.spd.trim_cache();
     }
@@ -286,13 +969,16 @@ async fn h_get(
     path: Path<String>,
     params: Query<BTreeMap<String, String>>,
     cookies: Cookies,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> ServerTrans {
     // Build the ServerTrans.
     let mut st = ServerTrans::new();
-    st.readonly = !params.0.get("save").is_some();
+    st.readonly = !params.0.contains_key("save");
     st.x.qy.path = path.0;
     st.x.qy.params = params.0;
-    st.x.qy.cookies = map_cookies(cookies);
+    st.x.qy.cookies = map_cookies(cookies, addr);
+    st.set_bearer(&headers);
 
     let mut wait_rx = ss.wait_tx.subscribe();
     st = ss.process(st).await;
@@ -305,26 +991,33 @@ async fn h_get(
                _ = tokio::time::sleep(core::time::Duration::from_secs(600)) => {}
             }
         }
+        if let Some((id, start)) = ext.snapshot {
+            st.x.rp.output = ss.snapshot_chunk(id, start).await;
+        }
     }
     ss.trim_cache();
     st
 }
 
 /// Handler for http POST requests.
+#[allow(clippy::too_many_arguments)]
 async fn h_post(
     state: Extension<Arc<SharedState>>,
     path: Path<String>,
     params: Query<BTreeMap<String, String>>,
     cookies: Cookies,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     form: Option<Form<BTreeMap<String, String>>>,
     multipart: Option<Multipart>,
 ) -> ServerTrans {
     // Build the Server Transaction.
     let mut st = ServerTrans::new();
-    st.readonly = params.0.get("readonly").is_some();
+    st.readonly = params.0.contains_key("readonly");
     st.x.qy.path = path.0;
     st.x.qy.params = params.0;
-    st.x.qy.cookies = map_cookies(cookies);
+    st.x.qy.cookies = map_cookies(cookies, addr);
+    st.set_bearer(&headers);
     if let Some(Form(form)) = form {
         st.x.qy.form = form;
     } else {
@@ -335,8 +1028,13 @@ async fn h_post(
 }
 
 use axum::{
-    body::{boxed, BoxBody, Full},
-    http::{header::HeaderName, status::StatusCode, HeaderValue, Response},
+    body::{boxed, Body, BoxBody, Full},
+    http::{
+        header::{HeaderName, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+        status::StatusCode,
+        HeaderValue, Method, Request, Response,
+    },
+    middleware::{self, Next},
     response::IntoResponse,
 };
 
@@ -353,104 +1051,659 @@ impl IntoResponse for ServerTrans {
                 HeaderValue::from_str(value).unwrap(),
             );
         }
+        if self.tid > 0 {
+            // Allows a replica that forwarded the request to wait until the update has been applied.
+            res.headers_mut()
+                .insert(TID_HEADER, HeaderValue::from(self.tid));
+        }
         res
     }
 }
 
-/// task for syncing with master database
-async fn sync_loop(rx: oneshot::Receiver<bool>, state: Arc<SharedState>) {
-    let db_is_new = rx.await.unwrap();
-    if db_is_new {
-        let sql = rget(state.clone(), "/ScriptExact?readonly").await;
-        let sql = std::str::from_utf8(&sql).unwrap().to_string();
-        let mut st = ServerTrans::new();
-        st.log = false;
-        st.x.qy.sql = Arc::new(sql);
-        state.process(st).await;
-        println!("New slave database initialised");
+/// Response header with Id of log.Transaction record for an update.
+const TID_HEADER: &str = "x-rustweb-tid";
+
+/// Does query string contain parameter name?
+fn has_param(query: Option<&str>, name: &str) -> bool {
+    query.is_some_and(|q| q.split('&').any(|p| p.split('=').next() == Some(name)))
+}
+
+/// Middleware that forwards requests that update the database from a replica to the master.
+/// Read-only requests are processed locally. The response is returned once the update has been applied by the replica.
+async fn forward(req: Request<Body>, next: Next<Body>) -> Response<BoxBody> {
+    let ss = req.extensions().get::<Arc<SharedState>>().unwrap().clone();
+    let query = req.uri().query();
+    let update = match *req.method() {
+        Method::GET => has_param(query, "save"),
+        Method::POST => !has_param(query, "readonly"),
+        _ => false,
+    };
+    // Promote must be processed by the replica itself.
+    if !update || ss.is_master() || req.uri().path() == "/Promote" {
+        return next.run(req).await;
     }
-    loop {
-        let tid = {
-            let apd = AccessPagedData::new_reader(state.spd.clone());
-            let db = Database::new(apd, "", state.bmap.clone());
-            let lt = db.table("log", "Transaction");
-            lt.id_gen.get()
-        };
-        let url = format!("/GetTransaction?k={tid}");
-        let ser = rget(state.clone(), &url).await;
-        if !ser.is_empty() {
-            let mut st = ServerTrans::new();
-            st.x.qy = bincode::deserialize(&ser).unwrap();
-            state.process(st).await;
-            println!("Slave database updated Transaction Id={tid}");
+
+    let (parts, body) = req.into_parts();
+    let url = ss.replicate_source.clone() + parts.uri.path_and_query().unwrap().as_str();
+    let mut headers = parts.headers;
+    headers.remove(HOST);
+    headers.remove(CONTENT_LENGTH);
+    let result = match hyper::body::to_bytes(body).await {
+        Ok(body) => {
+            ss.forward_client
+                .request(parts.method, url)
+                .headers(headers)
+                .body(body)
+                .send()
+                .await
+        }
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let r = match result {
+        Ok(r) => r,
+        Err(e) => {
+            let msg = format!("Error forwarding request to master: {e}");
+            return error_response(StatusCode::BAD_GATEWAY, msg);
         }
+    };
+    let status = r.status();
+    let mut headers = r.headers().clone();
+    let tid = headers.remove(TID_HEADER);
+    let body = r.bytes().await.unwrap_or_default();
+    if let Some(tid) = tid.and_then(|v| v.to_str().ok()?.parse::<i64>().ok()) {
+        ss.wait_applied(tid).await;
     }
+    headers.remove(TRANSFER_ENCODING);
+    headers.remove(CONNECTION);
+    let mut res = Response::builder().body(boxed(Full::from(body))).unwrap();
+    *res.status_mut() = status;
+    *res.headers_mut() = headers;
+    res
 }
 
-/// Sleep function that checks real time elapsed.
-async fn sleep_real(secs: u64) {
-    let start = std::time::SystemTime::now();
-    for _ in (0..secs).step_by(10) {
-        tokio::time::sleep(core::time::Duration::from_secs(10)).await;
-        match start.elapsed() {
-            Ok(e) => {
-                if e >= core::time::Duration::from_secs(secs) {
-                    return;
-                }
-            }
-            Err(_) => {
+/// Response with status code and text message.
+fn error_response(status: StatusCode, msg: String) -> Response<BoxBody> {
+    let mut res = Response::builder().body(boxed(Full::from(msg))).unwrap();
+    *res.status_mut() = status;
+    res
+}
+
+/// task for syncing with master database
+async fn sync_loop(rx: oneshot::Receiver<bool>, state: Arc<SharedState>) {
+    // Wait for database to be opened ( after bootstrap ).
+    let _ = rx.await.unwrap();
+    // Time of last verification, the first is when the replica starts.
+    let mut verified: Option<std::time::Instant> = None;
+    while !state.is_master() {
+        if state.verify > 0 && verified.is_none_or(|t| t.elapsed().as_secs() >= state.verify * 60) {
+            verified = Some(std::time::Instant::now());
+            if !verify(&state).await {
                 return;
             }
         }
+        if !sync_batch(&state, state.next_tid(), 1000).await {
+            return;
+        }
     }
 }
 
-/// Get data from master server, retries in case of error.
-async fn rget(state: Arc<SharedState>, query: &str) -> Vec<u8> {
-    // get a client builder
-    let client = reqwest::Client::builder()
-        .default_headers(reqwest::header::HeaderMap::new())
-        .build()
-        .unwrap();
-    loop {
-        let mut retry_delay = true;
-        let req = client
-            .get(state.replicate_source.clone() + query)
-            .header("Cookie", state.replicate_credentials.clone());
-
-        tokio::select! {
-            response = req.send() =>
-            {
-                match response
-                {
-                  Ok(r) => {
-                     let status = r.status();
-                     if status.is_success()
-                     {
-                         match r.bytes().await {
-                            Ok(b) => { return b.to_vec(); }
-                            Err(e) => { println!("rget failed to get bytes err={e}" ); }
-                         }
-                     } else {
-                         println!("rget bad response status = {status}");
-                     }
-                  }
-                  Err(e) => {
-                    println!("rget send error {e}");
-                  }
-               }
+/// Fetch and apply up to n transactions from master, starting at Id tid. Result is false if replication has stopped.
+async fn sync_batch(state: &Arc<SharedState>, tid: i64, n: i64) -> bool {
+    let url = format!(
+        "/GetTransactions?k={tid}&n={n}&pack=1&name={}",
+        url_encode(&state.replicate_name)
+    );
+    let data = rget(state.clone(), &url).await;
+    if !data.is_empty() && !state.is_master() {
+        let data = match inflate(&data) {
+            Ok(data) => data,
+            Err(e) => {
+                // For example the response was truncated, the batch is fetched again.
+                println!("Replication error fetching Transaction Id={tid}: {e}");
+                tokio::time::sleep(core::time::Duration::from_secs(10)).await;
+                return true;
             }
-            _ = sleep_real(800) =>
-            {
-              println!( "rget timed out after 800 seconds" );
-              retry_delay = false;
+        };
+        let mut batch = Vec::new();
+        let mut error = None;
+        for rec in unframe(&data) {
+            match read_log_record(&rec) {
+                Ok((qy, recorded)) => batch.push((rec, qy, recorded)),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
             }
         }
-        if retry_delay {
-            // Wait before retrying after error/timeout.
-            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        let n = batch.len() as i64;
+        if n > 0 {
+            let mut st = ServerTrans::new();
+            st.batch = batch;
+            state.process(st).await;
+            println!(
+                "Slave database updated Transaction Id={tid} to {}",
+                tid + n - 1
+            );
+        }
+        if let Some(e) = error {
+            println!(
+                "Replication stopped, cannot apply Transaction Id={}: {e}",
+                tid + n
+            );
+            return false;
         }
     }
+    true
+}
+
+/// Verify the replica matches the master, by comparing table checksums at the same transaction Id ( see /GetChecksums ).
+/// Tables that do not match are reported to the master, and re-fetched if the repair option is set.
+/// Requests to the master are not retried, if one fails verification is skipped ( until the next interval ).
+/// Result is false if replication has stopped.
+async fn verify(state: &Arc<SharedState>) -> bool {
+    let Some(data) = rget_once(state, "/GetChecksums").await else {
+        println!("Verify skipped, could not get checksums from master");
+        return true;
+    };
+    let text = String::from_utf8_lossy(&data);
+    let mut lines = text.lines();
+    let Some(k) = lines.next().and_then(|line| line.parse::<i64>().ok()) else {
+        println!("Verify failed, unexpected response from master");
+        return true;
+    };
+    let master: Vec<Vec<&str>> = lines.map(|line| line.split('\t').collect()).collect();
+
+    // Apply transactions up to k, so the replica is at the same point as the master.
+    loop {
+        let tid = state.next_tid();
+        if tid > k || state.is_master() {
+            break;
+        }
+        if !sync_batch(state, tid, (k + 1 - tid).min(1000)).await {
+            return false;
+        }
+    }
+    if state.next_tid() != k + 1 {
+        println!("Verify skipped, replica is not at Transaction Id={k}");
+        return true;
+    }
+    let mut diverged = diverged_tables(state, &master);
+    if state.repair && !diverged.is_empty() {
+        for (schema, name) in std::mem::take(&mut diverged) {
+            let url = format!(
+                "/GetTableScript?s={}&n={}",
+                url_encode(&schema),
+                url_encode(&name)
+            );
+            let script = rget_once(state, &url).await.unwrap_or_default();
+            let script = String::from_utf8_lossy(&script).to_string();
+            // The script can only be applied if the master has not been updated since the checksums were computed.
+            match script.split_once('\n') {
+                Some((id, sql)) if id.parse::<i64>() == Ok(k) && state.next_tid() == k + 1 => {
+                    let mut st = ServerTrans::new();
+                    st.x.qy.sql = Arc::new(sql.to_string());
+                    let st = state.process(st).await;
+                    if st.x.rp.err.is_empty() {
+                        println!("Verify repaired table {schema}.{name}");
+                        continue;
+                    }
+                    println!("Verify repair of {schema}.{name} failed: {}", st.x.rp.err);
+                }
+                _ => println!("Verify repair of {schema}.{name} skipped, master has been updated or did not respond"),
+            }
+            diverged.push((schema, name));
+        }
+    }
+    let list: Vec<String> = diverged.iter().map(|(s, n)| format!("{s}.{n}")).collect();
+    let list = list.join(" ");
+    if list.is_empty() {
+        println!("Verified replica matches master at Transaction Id={k}");
+    } else {
+        println!("Verify found tables that do not match master at Transaction Id={k}: {list}");
+    }
+    let url = format!(
+        "/ReplicaVerified?name={}&k={k}&diverged={}",
+        url_encode(&state.replicate_name),
+        url_encode(&list)
+    );
+    rget_once(state, &url).await;
+    true
+}
+
+/// Tables ( schema, name ) where the replica checksum does not match the master checksum ( schema, name, rows, hash ).
+fn diverged_tables(state: &SharedState, master: &[Vec<&str>]) -> Vec<(String, String)> {
+    let apd = AccessPagedData::new_reader(state.spd.clone());
+    let db = Database::new(apd, "", state.bmap.clone());
+    let mut result = Vec::new();
+    for m in master {
+        if m.len() == 4 && table_checksum(&db, m[0], m[1]) != format!("{} {}", m[2], m[3]) {
+            result.push((m[0].to_string(), m[1].to_string()));
+        }
+    }
+    result
+}
+
+/// Row count and SHA-1 hash ( hex ) of the rows of a table in Id order, separated by a space, e.g. "3 8d2e..."
+/// The hash is of the values, so does not depend on how the rows are stored. Result is empty if the table does not exist.
+fn table_checksum(db: &DB, schema: &str, name: &str) -> String {
+    use sha1::Digest;
+    let Some(t) = db.get_table(&ObjRef::new(schema, name)) else {
+        return String::new();
+    };
+    let mut h = sha1::Sha1::new();
+    let mut rows = 0;
+    for (pp, off) in t.scan(db) {
+        let p = &pp.borrow();
+        rows += 1;
+        h.update(&p.data[off..off + 8]);
+        for (c, typ) in t.info.typ.iter().enumerate() {
+            match Value::load(db, *typ, &p.data, off + t.info.off[c]).0 {
+                Value::Int(x) => h.update(x.to_le_bytes()),
+                Value::Float(x) => h.update(x.to_le_bytes()),
+                Value::Bool(x) => h.update([x as u8]),
+                Value::String(x) => {
+                    h.update((x.len() as u64).to_le_bytes());
+                    h.update(x.as_bytes());
+                }
+                Value::RcBinary(x) => {
+                    h.update((x.len() as u64).to_le_bytes());
+                    h.update(&x[..]);
+                }
+                Value::ArcBinary(x) => {
+                    h.update((x.len() as u64).to_le_bytes());
+                    h.update(&x[..]);
+                }
+                _ => {}
+            }
+        }
+    }
+    let hash: String = h.finalize().iter().map(|b| format!("{b:02x}")).collect();
+    format!("{rows} {hash}")
+}
+
+/// Request for chunk of snapshot starting at page, with channel for reply.
+type SnapshotRequest = (u64, oneshot::Sender<Vec<u8>>);
+
+/// File used to download snapshot.
+const SNAPSHOT_FILE: &str = "rustweb.snapshot";
+
+/// Start of snapshot header.
+const SNAPSHOT_MAGIC: &[u8] = b"RWS";
+
+/// Snapshot format version.
+const SNAPSHOT_FORMAT: u8 = 1;
+
+/// Size of snapshot header: SNAPSHOT_MAGIC, SNAPSHOT_FORMAT, id, log.Transaction id, number of pages.
+const SNAPSHOT_HSIZE: usize = 28;
+
+/// Size of snapshot page header: page number, length of data, CRC32 of data.
+const SNAPSHOT_PHSIZE: usize = 20;
+
+/// Length of data for a free page ( no data follows ).
+const SNAPSHOT_FREE: u64 = u64::MAX;
+
+/// Maximum number of snapshots being downloaded at the same time ( each holds a copy of the database ).
+const SNAPSHOT_MAX: usize = 4;
+
+/// A snapshot expires if no chunk is requested for this many seconds.
+const SNAPSHOT_EXPIRE: u64 = 600;
+
+/// Snapshot header.
+struct SnapshotHeader {
+    id: u64,
+    tid: u64,
+    pages: u64,
+}
+
+impl SnapshotHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::with_capacity(SNAPSHOT_HSIZE);
+        result.extend_from_slice(SNAPSHOT_MAGIC);
+        result.push(SNAPSHOT_FORMAT);
+        for x in [self.id, self.tid, self.pages] {
+            result.extend_from_slice(&x.to_le_bytes());
+        }
+        result
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() < SNAPSHOT_HSIZE || !b.starts_with(SNAPSHOT_MAGIC) || b[3] != SNAPSHOT_FORMAT {
+            return None;
+        }
+        let u = |i: usize| u64::from_le_bytes(b[i..i + 8].try_into().unwrap());
+        Some(Self {
+            id: u(4),
+            tid: u(12),
+            pages: u(20),
+        })
+    }
+}
+
+/// Task that serves chunks of a consistent snapshot of the database logical pages, until not used for 10 minutes.
+fn snapshot_task(
+    spd: Arc<SharedPagedData>,
+    bmap: Arc<BuiltinMap>,
+    id: u64,
+    rx: std::sync::mpsc::Receiver<SnapshotRequest>,
+) {
+    // Holding the file lock ensures the page count and free pages match the snapshot.
+    let (apd, pages, free) = {
+        let file = spd.file.read().unwrap();
+        let apd = AccessPagedData::new_reader(spd.clone());
+        (apd, file.stg.read_u64(8), file.get_info().0)
+    };
+    let db = Database::new(apd, "", bmap);
+    let tid = db.table("log", "Transaction").id_gen.get() as u64;
+    let header = SnapshotHeader { id, tid, pages }.encode();
+    while let Ok((start, reply)) = rx.recv_timeout(core::time::Duration::from_secs(SNAPSHOT_EXPIRE))
+    {
+        let mut chunk = header.clone();
+        let mut lp = start;
+        while lp < pages && chunk.len() < 4000000 {
+            chunk.extend_from_slice(&lp.to_le_bytes());
+            if free.contains(&lp) {
+                chunk.extend_from_slice(&SNAPSHOT_FREE.to_le_bytes());
+                chunk.extend_from_slice(&0u32.to_le_bytes());
+                lp += 1;
+                continue;
+            }
+            let data = db.file.get_page(lp);
+            chunk.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            chunk.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+            chunk.extend_from_slice(&data);
+            lp += 1;
+        }
+        let _ = reply.send(chunk);
+    }
+}
+
+/// Number of complete pages in data ( after snapshot header ) and their total length.
+/// The pages are checked against their CRC, if a page fails the check the result stops before it ( and the flag is set ).
+fn snapshot_pages(data: &[u8]) -> (u64, usize, bool) {
+    let (mut n, mut i) = (0, 0);
+    while i + SNAPSHOT_PHSIZE <= data.len() {
+        let (len, free) = match u64::from_le_bytes(data[i + 8..i + 16].try_into().unwrap()) {
+            SNAPSHOT_FREE => (0, true),
+            len => (len as usize, false),
+        };
+        if len > data.len() - i - SNAPSHOT_PHSIZE {
+            break;
+        }
+        let crc = u32::from_le_bytes(data[i + 16..i + 20].try_into().unwrap());
+        let page = &data[i + SNAPSHOT_PHSIZE..i + SNAPSHOT_PHSIZE + len];
+        if !free && crc32fast::hash(page) != crc {
+            return (n, i, true);
+        }
+        i += SNAPSHOT_PHSIZE + len;
+        n += 1;
+    }
+    (n, i, false)
+}
+
+/// Get initial copy of master database for a new replica.
+/// The snapshot is downloaded to SNAPSHOT_FILE ( so the download can be resumed if interrupted ), then written to the database file.
+/// If the API key has a replication filter, the master sends a script instead, which is the result ( see log.ScriptFiltered ).
+async fn bootstrap(state: Arc<SharedState>) -> Option<String> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    if state.spd.file.read().unwrap().stg.read_u64(8) != 0 {
+        // Database already has pages.
+        let _ = std::fs::remove_file(SNAPSHOT_FILE);
+        return None;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(SNAPSHOT_FILE)
+        .expect("Error opening snapshot file");
+
+    // Check for partial download.
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).unwrap();
+    let mut header = SnapshotHeader::decode(&data);
+    let (mut done, mut end) = match header {
+        Some(_) => {
+            let (n, len, _) = snapshot_pages(&data[SNAPSHOT_HSIZE..]);
+            (n, SNAPSHOT_HSIZE + len)
+        }
+        None => (0, 0),
+    };
+    drop(data);
+
+    loop {
+        let id = header.as_ref().map_or(0, |h| h.id);
+        let chunk = rget(state.clone(), &format!("/GetSnapshot?id={id}&start={done}")).await;
+        if id == 0 && !chunk.is_empty() && !chunk.starts_with(SNAPSHOT_MAGIC) {
+            drop(file);
+            let _ = std::fs::remove_file(SNAPSHOT_FILE);
+            println!("New slave database will be initialised from filtered script");
+            return Some(String::from_utf8_lossy(&chunk).to_string());
+        }
+        let h = match SnapshotHeader::decode(&chunk) {
+            Some(h) if id == 0 || h.id == id => h,
+            _ => {
+                println!("Snapshot not available, restarting download");
+                (header, done, end) = (None, 0, 0);
+                tokio::time::sleep(core::time::Duration::from_secs(10)).await;
+                continue;
+            }
+        };
+        if header.is_none() {
+            end = SNAPSHOT_HSIZE;
+        }
+        let (n, len, bad) = snapshot_pages(&chunk[SNAPSHOT_HSIZE..]);
+        file.set_len(end as u64).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&chunk[0..SNAPSHOT_HSIZE]).unwrap();
+        file.seek(SeekFrom::Start(end as u64)).unwrap();
+        file.write_all(&chunk[SNAPSHOT_HSIZE..SNAPSHOT_HSIZE + len])
+            .unwrap();
+        file.sync_data().unwrap();
+        done += n;
+        end += len;
+        println!("Snapshot pages downloaded={done} of {}", h.pages);
+        let finished = done >= h.pages;
+        header = Some(h);
+        if finished {
+            break;
+        }
+        if bad {
+            println!("Snapshot page CRC check failed, fetching the page again");
+            tokio::time::sleep(core::time::Duration::from_secs(1)).await;
+        }
+    }
+    let h = header.unwrap();
+
+    // Write the pages to the database file.
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    {
+        let mut cf = state.spd.file.write().unwrap();
+        for _ in 0..h.pages {
+            cf.alloc_page();
+        }
+        let mut i = SNAPSHOT_HSIZE;
+        for _ in 0..h.pages {
+            let u = |i: usize| u64::from_le_bytes(data[i..i + 8].try_into().unwrap());
+            let (lp, len) = (u(i), u(i + 8));
+            let crc = u32::from_le_bytes(data[i + 16..i + 20].try_into().unwrap());
+            i += SNAPSHOT_PHSIZE;
+            if len == SNAPSHOT_FREE {
+                cf.free_page(lp);
+                continue;
+            }
+            let page = &data[i..i + len as usize];
+            if crc32fast::hash(page) != crc {
+                // The pages were checked when downloaded, so the snapshot file is corrupt, it is downloaded again after a restart.
+                drop(file);
+                let _ = std::fs::remove_file(SNAPSHOT_FILE);
+                panic!("Snapshot page {lp} CRC check failed");
+            }
+            cf.set_page(lp, Arc::new(page.to_vec()));
+            i += len as usize;
+        }
+        cf.save();
+
+        // Reopen the file, so it is no longer considered new.
+        *cf = CompactFile::new(open_storage(), state.spd.sp_size, state.spd.ep_size);
+    }
+    drop(file);
+    let _ = std::fs::remove_file(SNAPSHOT_FILE);
+    println!(
+        "New slave database initialised from snapshot at Transaction Id={}",
+        h.tid
+    );
+    None
+}
+
+/// Split data into frames, each prefixed by its length ( see FRAMEADD ). A truncated final frame is ignored.
+fn unframe(data: &[u8]) -> Vec<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i + 8 <= data.len() {
+        let n = u64::from_le_bytes(data[i..i + 8].try_into().unwrap()) as usize;
+        i += 8;
+        if n > data.len() - i {
+            break;
+        }
+        result.push(data[i..i + n].to_vec());
+        i += n;
+    }
+    result
+}
+
+/// Percent-encode s for use in a URL query.
+fn url_encode(s: &str) -> String {
+    let mut result = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{b:02X}"));
+        }
+    }
+    result
+}
+
+/// Sleep function that checks real time elapsed.
+async fn sleep_real(secs: u64) {
+    let start = std::time::SystemTime::now();
+    for _ in (0..secs).step_by(10) {
+        tokio::time::sleep(core::time::Duration::from_secs(10)).await;
+        match start.elapsed() {
+            Ok(e) => {
+                if e >= core::time::Duration::from_secs(secs) {
+                    return;
+                }
+            }
+            Err(_) => {
+                return;
+            }
+        }
+    }
+}
+
+/// Get data from master server, retries in case of error. Result is empty if the replica has been promoted to master.
+async fn rget(state: Arc<SharedState>, query: &str) -> Vec<u8> {
+    // get a client builder
+    let client = reqwest::Client::builder()
+        .default_headers(reqwest::header::HeaderMap::new())
+        .build()
+        .unwrap();
+    while !state.is_master() {
+        let mut retry_delay = true;
+        let req = master_get(&client, &state, query);
+
+        tokio::select! {
+            response = req.send() =>
+            {
+                match response
+                {
+                  Ok(r) => {
+                     let status = r.status();
+                     if status.is_success()
+                     {
+                         match r.bytes().await {
+                            Ok(b) => { return b.to_vec(); }
+                            Err(e) => { println!("rget failed to get bytes err={e}" ); }
+                         }
+                     } else if status == reqwest::StatusCode::GONE {
+                         // Transaction has been pruned from master log.
+                         println!("rget {}", r.text().await.unwrap_or_default());
+                     } else {
+                         println!("rget bad response status = {status}");
+                     }
+                  }
+                  Err(e) => {
+                    println!("rget send error {e}");
+                  }
+               }
+            }
+            _ = sleep_real(800) =>
+            {
+              println!( "rget timed out after 800 seconds" );
+              retry_delay = false;
+            }
+            _ = promoted(&state) =>
+            {
+              retry_delay = false;
+            }
+        }
+        if retry_delay {
+            // Wait before retrying after error/timeout.
+            tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+        }
+    }
+    Vec::new()
+}
+
+/// Get data from master server with a single request ( no retry ). If the request fails, the error is printed and the result is None.
+async fn rget_once(state: &SharedState, query: &str) -> Option<Vec<u8>> {
+    let req = master_get(&reqwest::Client::new(), state, query);
+    match req
+        .timeout(core::time::Duration::from_secs(800))
+        .send()
+        .await
+    {
+        Ok(r) if r.status().is_success() => match r.bytes().await {
+            Ok(b) => Some(b.to_vec()),
+            Err(e) => {
+                println!("rget_once {query} failed to get bytes err={e}");
+                None
+            }
+        },
+        Ok(r) => {
+            println!("rget_once {query} bad response status = {}", r.status());
+            None
+        }
+        Err(e) => {
+            println!("rget_once {query} send error {e}");
+            None
+        }
+    }
+}
+
+/// Request to get data from master server, with the replication credentials.
+fn master_get(
+    client: &reqwest::Client,
+    state: &SharedState,
+    query: &str,
+) -> reqwest::RequestBuilder {
+    let req = client.get(state.replicate_source.clone() + query);
+    if state.replicate_key.is_empty() {
+        req.header("Cookie", state.replicate_credentials.clone())
+    } else {
+        req.bearer_auth(&state.replicate_key)
+    }
+}
+
+/// Completes when the replica has been promoted to master ( checked every second ).
+async fn promoted(state: &SharedState) {
+    while !state.is_master() {
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
 }
 
 /// task for sleeping - calls timed.Run once sleep time has elapsed.
@@ -461,7 +1714,7 @@ async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedState
             ns = rx.recv() => { sleep_micro = ns.unwrap(); }
             _ = tokio::time::sleep(core::time::Duration::from_micros(sleep_micro)) =>
             {
-              if state.is_master
+              if state.is_master()
               {
                 let mut st = ServerTrans::new();
                 st.x.qy.sql = Arc::new("EXEC timed.Run()".to_string());
@@ -473,16 +1726,25 @@ async fn sleep_loop(mut rx: mpsc::UnboundedReceiver<u64>, state: Arc<SharedState
 }
 
 /// task that sends emails
+/// Emails are sent in batches, one for each SmtpAccount, sent at the same time.
+/// The transport ( with its connection pool ) for an account is kept while the account is unchanged.
 async fn email_loop(mut rx: mpsc::UnboundedReceiver<()>, state: Arc<SharedState>) {
+    let senders: Arc<Mutex<BTreeMap<u64, AccountSender>>> = Arc::new(Mutex::new(BTreeMap::new()));
+    // Accounts with a batch being sent, their queued emails are read once the batch is complete.
+    let busy: Arc<Mutex<std::collections::BTreeSet<u64>>> = Arc::default();
     loop {
-        let mut send_list = Vec::new();
+        let mut batches: BTreeMap<u64, (SmtpAccount, Vec<(u64, EmailMsg)>)> = BTreeMap::new();
         {
             let _ = rx.recv().await;
+            let busy = busy.lock().unwrap().clone();
             let apd = AccessPagedData::new_reader(state.spd.clone());
             let db = Database::new(apd, "", state.bmap.clone());
             let qt = db.table("email", "Queue");
             let mt = db.table("email", "Msg");
             let at = db.table("email", "SmtpAccount");
+            let xt = db.table("email", "Attachment");
+            let rt = db.table("email", "Recipient");
+            let ft = db.table("web", "File");
 
             for (pp, off) in qt.scan(&db) {
                 let p = &pp.borrow();
@@ -497,52 +1759,356 @@ async fn email_loop(mut rx: mpsc::UnboundedReceiver<()>, state: Arc<SharedState>
                     let title = a.str(&db, 2);
                     let body = a.str(&db, 3);
                     let format = a.int(4);
-                    let account = a.int(5) as u64;
+                    let account_id = a.int(5) as u64;
+                    if busy.contains(&account_id) {
+                        continue;
+                    }
+                    let text = a.str(&db, 7);
+                    let mut to = vec![to];
+                    let mut cc = vec![a.str(&db, 8)];
+                    let mut bcc = vec![a.str(&db, 9)];
+                    let reply_to = a.str(&db, 10);
+                    let headers = a.str(&db, 11);
+
+                    for (pp, off) in rt.scan_key(&db, Value::Int(msg as i64), 0) {
+                        let p = &pp.borrow();
+                        let a = rt.access(p, off);
+                        let address = a.str(&db, 2);
+                        match a.int(1) {
+                            1 => cc.push(address),
+                            2 => bcc.push(address),
+                            _ => to.push(address),
+                        }
+                    }
 
-                    if let Some((pp, off)) = at.id_get(&db, account) {
+                    if let Some((pp, off)) = at.id_get(&db, account_id) {
                         let p = &pp.borrow();
                         let a = at.access(p, off);
-                        let server = a.str(&db, 0);
-                        let username = a.str(&db, 1);
-                        let password = a.str(&db, 2);
-
-                        send_list.push((
-                            msg,
-                            (from, to, title, body, format),
-                            (server, username, password),
-                        ));
+                        let account = SmtpAccount {
+                            server: a.str(&db, 0),
+                            username: a.str(&db, 1),
+                            password: a.str(&db, 2),
+                            port: a.int(3),
+                            security: a.int(4),
+                            mechanism: a.str(&db, 5),
+                            timeout: a.int(6),
+                            transport: a.int(7),
+                            connections: a.int(8),
+                            ratelimit: a.int(9),
+                            dkim_domain: a.str(&db, 13),
+                            dkim_selector: a.str(&db, 14),
+                            dkim_key: a.str(&db, 15),
+                        };
+
+                        let email = EmailMsg {
+                            from,
+                            to: to.join(","),
+                            cc: cc.join(","),
+                            bcc: bcc.join(","),
+                            reply_to,
+                            headers,
+                            title,
+                            body,
+                            format,
+                            text,
+                            attachments: email_attachments(&db, &xt, &ft, msg),
+                        };
+                        batches
+                            .entry(account_id)
+                            .or_insert_with(|| (account, Vec::new()))
+                            .1
+                            .push((msg, email));
                     }
                 }
             }
         }
-        for (msg, email, account) in send_list {
-            let blocking_task = tokio::task::spawn_blocking(move || send_email(email, account));
-            let result = blocking_task.await.unwrap();
-            match result {
-                Ok(_) => email_sent(&state, msg).await,
-                Err(e) => match e {
-                    EmailError::Address(ae) => {
-                        email_error(&state, msg, 0, ae.to_string()).await;
-                    }
-                    EmailError::Lettre(le) => {
-                        email_error(&state, msg, 0, le.to_string()).await;
-                    }
-                    EmailError::Send(se) => {
-                        let retry = if se.is_transient() { 1 } else { 0 };
-                        email_error(&state, msg, retry, se.to_string()).await;
+        // Each account's batch is sent independently, so a slow account does not delay the others.
+        for (id, (account, emails)) in batches {
+            busy.lock().unwrap().insert(id);
+            let cached = senders
+                .lock()
+                .unwrap()
+                .remove(&id)
+                .filter(|s| s.account == account);
+            let (sender, next) = match cached {
+                Some(s) => (Ok(s.sender), s.next),
+                None => (
+                    email_sender(&account).map(Arc::new),
+                    tokio::time::Instant::now(),
+                ),
+            };
+            let (state, senders, busy) = (state.clone(), senders.clone(), busy.clone());
+            tokio::spawn(async move {
+                let next = match &sender {
+                    Ok(sender) => send_batch(&state, sender, &account, next, emails).await,
+                    Err(e) => {
+                        for (msg, _) in emails {
+                            email_error(&state, msg, 0, e.clone()).await;
+                        }
+                        next
                     }
-                },
+                };
+                if let Ok(sender) = sender {
+                    let s = AccountSender {
+                        account,
+                        sender,
+                        next,
+                    };
+                    senders.lock().unwrap().insert(id, s);
+                }
+                busy.lock().unwrap().remove(&id);
+                // Emails may have been queued for the account while the batch was being sent.
+                let _ = state.email_tx.send(());
+            });
+        }
+    }
+}
+
+/// Default maximum number of emails sent at the same time for an account.
+const EMAIL_CONNECTIONS: usize = 4;
+
+/// Upper limit for the connections setting of an account.
+const EMAIL_CONNECTIONS_MAX: i64 = 100;
+
+/// Send a batch of emails for an account, at most connections at the same time, and at most ratelimit per minute.
+/// Next is the earliest time the next email can be sent, the updated value is returned.
+async fn send_batch(
+    state: &Arc<SharedState>,
+    sender: &Arc<EmailSender>,
+    account: &SmtpAccount,
+    mut next: tokio::time::Instant,
+    emails: Vec<(u64, EmailMsg)>,
+) -> tokio::time::Instant {
+    let connections = account.max_connections();
+    let gap = match account.ratelimit {
+        n if n > 0 => core::time::Duration::from_micros(60_000_000 / n as u64),
+        _ => core::time::Duration::ZERO,
+    };
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(connections));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (msg, email) in emails {
+        tokio::time::sleep_until(next).await;
+        next = next.max(tokio::time::Instant::now()) + gap;
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let (state, sender) = (state.clone(), sender.clone());
+        tasks.spawn(async move {
+            email_sending(&state, msg).await;
+            let blocking_task = tokio::task::spawn_blocking(move || {
+                let mut errors = Vec::new();
+                let result = send_email(email, &sender, &mut errors);
+                (errors, result)
+            });
+            let (errors, result) = blocking_task.await.unwrap();
+            drop(permit);
+            email_result(&state, msg, errors, result).await;
+        });
+    }
+    while tasks.join_next().await.is_some() {}
+    next
+}
+
+/// Update the database with the result of sending an email.
+async fn email_result(
+    state: &SharedState,
+    msg: u64,
+    errors: Vec<String>,
+    result: Result<(String, String), EmailError>,
+) {
+    for e in errors {
+        email_recipient_error(state, msg, e).await;
+    }
+    match result {
+        Ok((response, message_id)) => email_sent(state, msg, response, message_id).await,
+        Err(e) => match e {
+            EmailError::Address(ae) => {
+                email_error(state, msg, 0, ae.to_string()).await;
+            }
+            EmailError::Lettre(le) => {
+                email_error(state, msg, 0, le.to_string()).await;
+            }
+            EmailError::File(fe) => {
+                email_error(state, msg, 0, fe.to_string()).await;
+            }
+            EmailError::Sendmail(se) => {
+                email_error(state, msg, 0, se.to_string()).await;
+            }
+            EmailError::Send(se) => {
+                let retry = if se.is_transient() { 1 } else { 0 };
+                email_error(state, msg, retry, se.to_string()).await;
+            }
+        },
+    }
+}
+
+/// Email to be sent, from email.Msg.
+struct EmailMsg {
+    from: String,
+    /// Comma separated addresses ( including those from email.Recipient ).
+    to: String,
+    cc: String,
+    bcc: String,
+    reply_to: String,
+    /// Extra headers, one per line.
+    headers: String,
+    title: String,
+    body: String,
+    /// 0 means body is plain text, 1 means HTML.
+    format: i64,
+    /// Plain text alternative for an HTML body ( generated from the HTML if blank ).
+    text: String,
+    attachments: Vec<EmailAttachment>,
+}
+
+/// Attachment filename, content type and content.
+type EmailAttachment = (String, String, Vec<u8>);
+
+/// Get the attachments for an email from email.Attachment ( in Id order ).
+/// If file is not zero, the content ( and default filename and content type ) comes from web.File.
+fn email_attachments(db: &DB, xt: &Rc<Table>, ft: &Rc<Table>, msg: u64) -> Vec<EmailAttachment> {
+    let mut result = Vec::new();
+    for (pp, off) in xt.scan_key(db, Value::Int(msg as i64), 0) {
+        let p = &pp.borrow();
+        let a = xt.access(p, off);
+        let mut filename = a.str(db, 1);
+        let mut content_type = a.str(db, 2);
+        let mut content = Value::load(db, xt.info.typ[3], &p.data, off + xt.info.off[3])
+            .0
+            .bin();
+        let file = a.int(4) as u64;
+        if file != 0 {
+            let Some((pp, off)) = ft.id_get(db, file) else {
+                continue;
+            };
+            let p = &pp.borrow();
+            let a = ft.access(p, off);
+            if filename.is_empty() {
+                let path = a.str(db, 0);
+                filename = path.rsplit('/').next().unwrap_or_default().to_string();
+            }
+            if content_type.is_empty() {
+                content_type = a.str(db, 1);
             }
+            content = Value::load(db, ft.info.typ[3], &p.data, off + ft.info.off[3])
+                .0
+                .bin();
+        }
+        if content_type.is_empty() {
+            content_type = "application/octet-stream".to_string();
+        }
+        result.push((filename, content_type, content.to_vec()));
+    }
+    result
+}
+
+/// SMTP server settings, from email.SmtpAccount.
+#[derive(PartialEq)]
+struct SmtpAccount {
+    /// SMTP server, or directory for file transport, or command for sendmail transport.
+    server: String,
+    username: String,
+    password: String,
+    /// 0 means the default port for the security mode.
+    port: i64,
+    /// 0 means STARTTLS, 1 means implicit TLS, 2 means no TLS.
+    security: i64,
+    /// Space separated authentication mechanisms ( PLAIN LOGIN XOAUTH2 ), blank means PLAIN.
+    mechanism: String,
+    /// Timeout in seconds, 0 means the default.
+    timeout: i64,
+    /// 0 means SMTP, 1 means write .eml files to a directory, 2 means sendmail.
+    transport: i64,
+    /// Maximum connections ( and emails sent at the same time ), 0 means the default, at most EMAIL_CONNECTIONS_MAX.
+    connections: i64,
+    /// Maximum emails sent per minute, 0 means no limit.
+    ratelimit: i64,
+    /// Domain for DKIM signature, blank means emails are not signed.
+    dkim_domain: String,
+    dkim_selector: String,
+    /// RSA private key ( PEM ) for DKIM signature.
+    dkim_key: String,
+}
+
+impl SmtpAccount {
+    /// Maximum connections ( and emails sent at the same time ), from 1 to EMAIL_CONNECTIONS_MAX.
+    fn max_connections(&self) -> usize {
+        match self.connections {
+            0 => EMAIL_CONNECTIONS,
+            n => n.clamp(1, EMAIL_CONNECTIONS_MAX) as usize,
         }
     }
 }
 
+/// Transport for sending email, with DKIM settings, created from an email.SmtpAccount.
+struct EmailSender {
+    transport: EmailTransport,
+    dkim: Option<lettre::message::DkimConfig>,
+}
+
+/// Transport for sending email.
+enum EmailTransport {
+    Smtp(lettre::SmtpTransport),
+    File(lettre::FileTransport),
+    Sendmail(lettre::SendmailTransport),
+}
+
+/// Cached transport for an account, with the earliest time the next email can be sent ( see send_batch ).
+struct AccountSender {
+    account: SmtpAccount,
+    sender: Arc<EmailSender>,
+    next: tokio::time::Instant,
+}
+
+/// Create transport for an account.
+fn email_sender(account: &SmtpAccount) -> Result<EmailSender, String> {
+    use lettre::{
+        message::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey},
+        FileTransport, SendmailTransport,
+    };
+    let transport = match account.transport {
+        0 => EmailTransport::Smtp(smtp_transport(account)?),
+        1 => EmailTransport::File(FileTransport::new(&account.server)),
+        2 => EmailTransport::Sendmail(if account.server.is_empty() {
+            SendmailTransport::new()
+        } else {
+            SendmailTransport::new_with_command(&account.server)
+        }),
+        x => return Err(format!("Invalid transport {x}")),
+    };
+    let dkim = if account.dkim_domain.is_empty() {
+        None
+    } else {
+        // DkimSigningKey requires PKCS#1, so the key is converted in case it is PKCS#8.
+        use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+        let key = rsa_private_key(&account.dkim_key)
+            .and_then(|k| k.to_pkcs1_pem(LineEnding::LF).ok())
+            .ok_or("Invalid DKIM key")?;
+        let key = DkimSigningKey::new(&key, DkimSigningAlgorithm::Rsa)
+            .map_err(|e| format!("Invalid DKIM key: {e}"))?;
+        Some(DkimConfig::default_config(
+            account.dkim_selector.clone(),
+            account.dkim_domain.clone(),
+            key,
+        ))
+    };
+    Ok(EmailSender { transport, dkim })
+}
+
+/// Parse RSA private key in PEM format ( PKCS#1 or PKCS#8 ).
+fn rsa_private_key(pem: &str) -> Option<rsa::RsaPrivateKey> {
+    use rsa::{pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey};
+    rsa::RsaPrivateKey::from_pkcs1_pem(pem)
+        .ok()
+        .or_else(|| rsa::RsaPrivateKey::from_pkcs8_pem(pem).ok())
+}
+
 /// Error enum for send_email
 #[derive(Debug)]
 enum EmailError {
     Address(lettre::address::AddressError),
     Lettre(lettre::error::Error),
     Send(lettre::transport::smtp::Error),
+    File(lettre::transport::file::Error),
+    Sendmail(lettre::transport::sendmail::Error),
 }
 
 impl From<lettre::address::AddressError> for EmailError {
@@ -563,197 +2129,1101 @@ impl From<lettre::transport::smtp::Error> for EmailError {
     }
 }
 
-/// Send an email using lettre.
-fn send_email(
-    (from, to, title, body, format): (String, String, String, String, i64),
-    (server, username, password): (String, String, String),
-) -> Result<(), EmailError> {
-    use lettre::{
-        message::SinglePart,
-        transport::smtp::{
-            authentication::{Credentials, Mechanism},
-            PoolConfig,
-        },
-        Message, SmtpTransport, Transport,
-    };
+impl From<lettre::transport::file::Error> for EmailError {
+    fn from(e: lettre::transport::file::Error) -> Self {
+        EmailError::File(e)
+    }
+}
 
-    let body = match format {
-        1 => SinglePart::html(body),
-        _ => SinglePart::plain(body),
-    };
+impl From<lettre::transport::sendmail::Error> for EmailError {
+    fn from(e: lettre::transport::sendmail::Error) -> Self {
+        EmailError::Sendmail(e)
+    }
+}
+
+/// Raw email header, for extra headers such as List-Unsubscribe.
+#[derive(Clone)]
+struct RawHeader(lettre::message::header::HeaderValue);
 
-    let email = Message::builder()
-        .to(to.parse()?)
-        .from(from.parse()?)
-        .subject(title)
-        .singlepart(body)?;
+impl lettre::message::header::Header for RawHeader {
+    fn name() -> lettre::message::header::HeaderName {
+        // Not used, as the name is included in the value.
+        lettre::message::header::HeaderName::new_from_ascii_str("X-Raw")
+    }
 
-    // Create TLS transport on port 587 with STARTTLS
-    let sender = SmtpTransport::starttls_relay(&server)?
-        // Add credentials for authentication
-        .credentials(Credentials::new(username, password))
-        // Configure expected authentication mechanism
-        .authentication(vec![Mechanism::Plain])
-        // Connection pool settings
-        .pool_config(PoolConfig::new().max_size(20))
-        .build();
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Err(format!("raw header cannot be parsed: {s}").into())
+    }
+
+    fn display(&self) -> lettre::message::header::HeaderValue {
+        self.0.clone()
+    }
+}
+
+/// Headers that are set from the other email.Msg columns ( or by lettre ), so cannot be given in email.Msg headers.
+const RESERVED_HEADERS: [&str; 16] = [
+    "From",
+    "Sender",
+    "To",
+    "Cc",
+    "Bcc",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "Message-ID",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "Content-Disposition",
+    "DKIM-Signature",
+    "Return-Path",
+    "Received",
+];
+
+/// Split a comma separated list of addresses, allowing commas in quoted names.
+fn split_addresses(list: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let (mut start, mut quoted) = (0, false);
+    for (i, c) in list.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                result.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&list[start..]);
+    result
+        .into_iter()
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// Send an email using lettre, result is the response and Message-ID.
+/// Invalid recipient addresses and headers are skipped, the errors are added to errors.
+fn send_email(
+    email: EmailMsg,
+    sender: &EmailSender,
+    errors: &mut Vec<String>,
+) -> Result<(String, String), EmailError> {
+    use lettre::{
+        message::{
+            header::{ContentType, HeaderName, HeaderValue},
+            Attachment, Mailbox, MultiPart, SinglePart,
+        },
+        Message, Transport,
+    };
+
+    let mut builder = Message::builder()
+        .from(email.from.parse()?)
+        .subject(email.title)
+        .message_id(None);
+
+    let lists: [(&str, &str); 4] = [
+        ("To", &email.to),
+        ("Cc", &email.cc),
+        ("Bcc", &email.bcc),
+        ("Reply-To", &email.reply_to),
+    ];
+    for (kind, list) in lists {
+        for address in split_addresses(list) {
+            match address.parse::<Mailbox>() {
+                Ok(mut mbox) => {
+                    // Quotes around a name are not removed by parse.
+                    mbox.name = mbox.name.map(|n| n.trim_matches('"').to_string());
+                    builder = match kind {
+                        "To" => builder.to(mbox),
+                        "Cc" => builder.cc(mbox),
+                        "Bcc" => builder.bcc(mbox),
+                        _ => builder.reply_to(mbox),
+                    }
+                }
+                Err(e) => errors.push(format!("{kind} address {address}: {e}")),
+            }
+        }
+    }
+
+    for line in email.headers.lines().filter(|l| !l.trim().is_empty()) {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        if RESERVED_HEADERS
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(name.trim()))
+        {
+            errors.push(format!("Header {line}: reserved header name"));
+            continue;
+        }
+        match HeaderName::new_from_ascii(name.trim().to_string()) {
+            Ok(name) => {
+                let value = HeaderValue::new(name, value.trim().to_string());
+                builder = builder.header(RawHeader(value));
+            }
+            Err(e) => errors.push(format!("Header {line}: {e}")),
+        }
+    }
+
+    enum Body {
+        Plain(SinglePart),
+        Alternative(MultiPart),
+    }
+
+    // An HTML body is sent with a plain text alternative.
+    let body = match email.format {
+        1 => {
+            let text = if email.text.is_empty() {
+                html_to_text(&email.body)
+            } else {
+                email.text
+            };
+            Body::Alternative(MultiPart::alternative_plain_html(text, email.body))
+        }
+        _ => Body::Plain(SinglePart::plain(email.body)),
+    };
+
+    let mut email = if email.attachments.is_empty() {
+        match body {
+            Body::Plain(part) => builder.singlepart(part)?,
+            Body::Alternative(parts) => builder.multipart(parts)?,
+        }
+    } else {
+        let mut mixed = match body {
+            Body::Plain(part) => MultiPart::mixed().singlepart(part),
+            Body::Alternative(parts) => MultiPart::mixed().multipart(parts),
+        };
+        for (filename, content_type, content) in email.attachments {
+            let content_type = ContentType::parse(&content_type)
+                .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap());
+            mixed = mixed.singlepart(Attachment::new(filename).body(content, content_type));
+        }
+        builder.multipart(mixed)?
+    };
+
+    if let Some(dkim) = &sender.dkim {
+        email.sign(dkim);
+    }
+
+    let message_id = email.headers().get_raw("Message-ID").unwrap_or_default();
+    let response = match &sender.transport {
+        EmailTransport::Smtp(t) => {
+            let r = t.send(&email)?;
+            format!("{} {}", r.code(), r.message().collect::<Vec<_>>().join(" "))
+        }
+        EmailTransport::File(t) => format!("Written {}.eml", t.send(&email)?),
+        EmailTransport::Sendmail(t) => {
+            t.send(&email)?;
+            "Sent using sendmail".to_string()
+        }
+    };
+    Ok((response, message_id.to_string()))
+}
+
+/// Create SMTP transport for an account.
+fn smtp_transport(account: &SmtpAccount) -> Result<lettre::SmtpTransport, String> {
+    use lettre::{
+        transport::smtp::{
+            authentication::{Credentials, Mechanism},
+            PoolConfig,
+        },
+        SmtpTransport,
+    };
+
+    let mut builder = match account.security {
+        // STARTTLS, default port 587.
+        0 => SmtpTransport::starttls_relay(&account.server).map_err(|e| e.to_string())?,
+        // Implicit TLS, default port 465.
+        1 => SmtpTransport::relay(&account.server).map_err(|e| e.to_string())?,
+        // No TLS, default port 25, e.g. for a local relay or a test server.
+        2 => SmtpTransport::builder_dangerous(&account.server),
+        x => return Err(format!("Invalid security mode {x}")),
+    };
+    if account.port != 0 {
+        let port =
+            u16::try_from(account.port).map_err(|_| format!("Invalid port {}", account.port))?;
+        builder = builder.port(port);
+    }
+    if account.timeout != 0 {
+        let timeout = u64::try_from(account.timeout)
+            .map_err(|_| format!("Invalid timeout {}", account.timeout))?;
+        builder = builder.timeout(Some(core::time::Duration::from_secs(timeout)));
+    }
+    if !account.username.is_empty() {
+        let mut mechanisms = Vec::new();
+        for m in account.mechanism.split_whitespace() {
+            mechanisms.push(match m.to_ascii_uppercase().as_str() {
+                "PLAIN" => Mechanism::Plain,
+                "LOGIN" => Mechanism::Login,
+                "XOAUTH2" => Mechanism::Xoauth2,
+                _ => return Err(format!("Invalid mechanism {m}")),
+            });
+        }
+        if mechanisms.is_empty() {
+            mechanisms.push(Mechanism::Plain);
+        }
+        builder = builder
+            .credentials(Credentials::new(
+                account.username.clone(),
+                account.password.clone(),
+            ))
+            .authentication(mechanisms);
+    }
+    // Connection pool settings, connections are kept for reuse.
+    let connections = account.max_connections() as u32;
+    Ok(builder
+        .pool_config(PoolConfig::new().max_size(connections))
+        .build())
+}
+
+/// Plain text version of HTML, for the text alternative of an HTML email.
+/// Tags are removed ( with the content of script and style elements ), block elements start a new line,
+/// and common character references are decoded.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    let mut skip = "";
+    while let Some(start) = rest.find('<') {
+        if skip.is_empty() {
+            text.push_str(&rest[..start]);
+        }
+        let end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |e| start + e + 1);
+        let tag = rest[start + 1..end.max(start + 1)]
+            .trim_start_matches('/')
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let closing = rest[start + 1..].starts_with('/');
+        if !skip.is_empty() {
+            if closing && tag == skip {
+                skip = "";
+            }
+        } else if !closing && (tag == "script" || tag == "style") {
+            skip = if tag == "script" { "script" } else { "style" };
+        } else if matches!(
+            tag.as_str(),
+            "br" | "p" | "div" | "tr" | "li" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "table"
+        ) {
+            text.push('\n');
+        }
+        rest = &rest[end..];
+    }
+    if skip.is_empty() {
+        text.push_str(rest);
+    }
+
+    // Collapse white space within lines, and blank lines.
+    let mut result = String::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() {
+            result.push_str(&line);
+            result.push('\n');
+        }
+    }
+    result
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Update the database to reflect an email is being sent.
+async fn email_sending(state: &SharedState, msg: u64) {
+    let mut st = ServerTrans::new();
+    st.x.qy.sql = Arc::new(format!("EXEC email.Sending({})", msg));
+    state.process(st).await;
+}
+
+/// Update the database to reflect an email was sent.
+async fn email_sent(state: &SharedState, msg: u64, response: String, message_id: String) {
+    let mut st = ServerTrans::new();
+    let src = format!(
+        "EXEC email.Sent({},'{}','{}')",
+        msg,
+        response.replace('\'', "''"),
+        message_id.replace('\'', "''")
+    );
+    st.x.qy.sql = Arc::new(src);
+    state.process(st).await;
+}
+
+/// Record an invalid recipient address ( or header ) for an email.
+async fn email_recipient_error(state: &SharedState, msg: u64, err: String) {
+    let mut st = ServerTrans::new();
+    let src = format!(
+        "EXEC email.LogRecipientError({},'{}')",
+        msg,
+        err.replace('\'', "''")
+    );
+    st.x.qy.sql = Arc::new(src);
+    state.process(st).await;
+}
+
+/// Update the database to reflect an error occurred sending an email.
+async fn email_error(state: &SharedState, msg: u64, retry: i8, err: String) {
+    let mut st = ServerTrans::new();
+    let src = format!(
+        "EXEC email.LogSendError({},{},'{}')",
+        msg,
+        retry,
+        err.replace('\'', "''")
+    );
+    st.x.qy.sql = Arc::new(src);
+    state.process(st).await;
+}
+
+/////////////////////////////////////////////
+// Helper functions for building ServerTrans.
+
+/// Get BTreeMap of cookies from Cookies.
+/// The client ip address is included as "ip" ( see web.ClientIp ).
+/// A cookie named "authorization" is ignored, as older versions of web.Bearer read the bearer token from there.
+fn map_cookies(cookies: Cookies, addr: SocketAddr) -> BTreeMap<String, String> {
+    let mut result = BTreeMap::new();
+    for cookie in cookies.list() {
+        let (name, value) = cookie.name_value();
+        if name != "authorization" {
+            result.insert(name.to_string(), value.to_string());
+        }
+    }
+    result.insert("ip".to_string(), addr.ip().to_string());
+    result
+}
+
+/// Get Vec of Parts from MultiPart.
+async fn map_parts(mp: Option<Multipart>) -> Vec<Part> {
+    let mut result = Vec::new();
+    if let Some(mut mp) = mp {
+        while let Some(field) = mp.next_field().await.unwrap() {
+            let name = field.name().unwrap().to_string();
+            let file_name = match field.file_name() {
+                Some(s) => s.to_string(),
+                None => "".to_string(),
+            };
+            let content_type = match field.content_type() {
+                Some(s) => s.to_string(),
+                None => "".to_string(),
+            };
+            let mut data = Vec::new();
+            let mut text = "".to_string();
+            if content_type.is_empty() {
+                if let Ok(s) = field.text().await {
+                    text = s;
+                }
+            } else if let Ok(bytes) = field.bytes().await {
+                data = bytes.to_vec()
+            }
+            let mut part = Part::default();
+            part.name = name;
+            part.file_name = file_name;
+            part.content_type = content_type;
+            part.data = Arc::new(data);
+            part.text = text;
+            result.push(part);
+        }
+    }
+    result
+}
+
+/////////////////////////////
+
+use argon2rs::argon2i_simple;
+
+/// Compile call to ARGON.
+fn c_argon(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let password = c_value(b, &mut args[0]);
+    let salt = c_value(b, &mut args[1]);
+    Box::new(Argon { password, salt })
+}
+
+/// Compiled call to ARGON.
+struct Argon {
+    password: CExpPtr<Value>,
+    salt: CExpPtr<Value>,
+}
+impl CExp<Value> for Argon {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let pw = self.password.eval(ee, d).str();
+        let salt = self.salt.eval(ee, d).str();
+
+        let result = argon2i_simple(&pw, &salt).to_vec();
+        Value::RcBinary(Rc::new(result))
+    }
+}
+
+/// Compile call to SHAHASH.
+fn c_sha_hash(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let s = c_value(b, &mut args[0]);
+    Box::new(ShaHash { s })
+}
+
+/// Compiled call to SHAHASH ( SHA-256 hash of a string, e.g. a random API key secret ).
+struct ShaHash {
+    s: CExpPtr<Value>,
+}
+impl CExp<Value> for ShaHash {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        use sha2::Digest;
+        let s = self.s.eval(ee, d).str();
+        let result = sha2::Sha256::digest(s.as_bytes()).to_vec();
+        Value::RcBinary(Rc::new(result))
+    }
+}
+
+/// Compile call to SLEEP.
+fn c_sleep(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let to = c_int(b, &mut args[0]);
+    Box::new(Sleep { to })
+}
+
+/// Compiled call to SLEEP
+struct Sleep {
+    to: CExpPtr<i64>,
+}
+impl CExp<i64> for Sleep {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let to = self.to.eval(ee, d);
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.sleep = if to <= 0 { 1 } else { to as u64 };
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to EMAILTX.
+fn c_email_tx(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(EmailTx {})
+}
+
+/// Compiled call to EMAILTX
+struct EmailTx {}
+impl CExp<i64> for EmailTx {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.tx_email = true;
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to TRANSWAIT.
+fn c_trans_wait(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(TransWait {})
+}
+
+/// Compiled call to TRANSWAIT
+struct TransWait {}
+impl CExp<i64> for TransWait {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.trans_wait = true;
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to RANDOMBYTES.
+fn c_random_bytes(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::Int]);
+    let n = c_int(b, &mut args[0]);
+    Box::new(RandomBytes { n })
+}
+
+/// Compiled call to RANDOMBYTES.
+struct RandomBytes {
+    n: CExpPtr<i64>,
+}
+impl CExp<Value> for RandomBytes {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let n = self.n.eval(ee, d).clamp(0, 1024) as usize;
+        let result = replayable(ee, || {
+            let mut result = vec![0; n];
+            getrandom::getrandom(&mut result).unwrap();
+            result
+        });
+        Value::RcBinary(Rc::new(result))
+    }
+}
+
+/// Compile call to BEARER.
+fn c_bearer(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    Box::new(Bearer {})
+}
+
+/// Compiled call to BEARER ( bearer token from the Authorization header ).
+/// The token is not saved in the log record, so when a replica applies the transaction the result is empty.
+struct Bearer {}
+impl CExp<Value> for Bearer {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let ext = ee.tr.get_extension();
+        let result = match ext.downcast_ref::<TransExt>() {
+            Some(ext) => ext.bearer.clone(),
+            None => String::new(),
+        };
+        ee.tr.set_extension(ext);
+        Value::String(Rc::new(result))
+    }
+}
+
+/// Compile call to RECORDED.
+fn c_recorded(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int]);
+    let x = c_int(b, &mut args[0]);
+    Box::new(Recorded { x })
+}
+
+/// Compiled call to RECORDED. The result is x, recorded in the log record, so when a replica applies the transaction
+/// the result is the same as on the master ( e.g. for a result that depends on the bearer token, see BEARER ).
+struct Recorded {
+    x: CExpPtr<i64>,
+}
+impl CExp<i64> for Recorded {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let x = self.x.eval(ee, d);
+        let result = replayable(ee, || x.to_le_bytes().to_vec());
+        i64::from_le_bytes(result.try_into().unwrap_or_default())
+    }
+}
+
+/// Compile call to DKIMKEY.
+fn c_dkim_key(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int, DataKind::Int]);
+    let account = c_int(b, &mut args[0]);
+    let user = c_int(b, &mut args[1]);
+    Box::new(DkimKey { account, user })
+}
+
+/// Compiled call to DKIMKEY( account, user ).
+/// Once the transaction completes, a new key is generated and saved by email.SetDkimKey ( as a separate transaction ).
+/// Ignored if console SQL is being run ( see SQLHISTORY ), or the request is read-only.
+struct DkimKey {
+    account: CExpPtr<i64>,
+    user: CExpPtr<i64>,
+}
+impl CExp<i64> for DkimKey {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let k = DkimKeyRequest {
+            account: self.account.eval(ee, d),
+            user: self.user.eval(ee, d),
+            path: ee.tr.arg(0, "").to_string(),
+            ip: ee.tr.arg(3, "ip").to_string(),
+        };
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            if !ext.console && !ext.readonly {
+                ext.dkim_key = Some(k);
+            }
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// New 2048 bit RSA private key in PKCS#1 PEM format, for DKIM signing.
+fn dkim_key() -> String {
+    use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+    let key = rsa::RsaPrivateKey::new(&mut rand_core::OsRng, 2048).unwrap();
+    key.to_pkcs1_pem(LineEnding::LF).unwrap().to_string()
+}
+
+/// Compile call to DKIMRECORD.
+fn c_dkim_record(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let key = c_value(b, &mut args[0]);
+    Box::new(DkimRecord { key })
+}
+
+/// Compiled call to DKIMRECORD ( DNS TXT record value for a DKIM private key, empty if the key is invalid ).
+struct DkimRecord {
+    key: CExpPtr<Value>,
+}
+impl CExp<Value> for DkimRecord {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        use rsa::pkcs8::{EncodePublicKey, LineEnding};
+        let key = self.key.eval(ee, d).str();
+        let result = rsa_private_key(&key)
+            .and_then(|k| k.to_public_key().to_public_key_pem(LineEnding::LF).ok())
+            .map(|pem| {
+                let p: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+                format!("v=DKIM1; k=rsa; p={p}")
+            })
+            .unwrap_or_default();
+        Value::String(Rc::new(result))
+    }
+}
+
+/// Result of a non-deterministic builtin. The result is recorded in the log record, and when a replica applies
+/// the transaction the recorded result is replayed, so the replica data is identical to the master.
+fn replayable(ee: &mut EvalEnv, f: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+    let mut ext = ee.tr.get_extension();
+    let result = match ext.downcast_mut::<TransExt>() {
+        Some(ext) => {
+            let result = ext.replay.pop_front().unwrap_or_else(f);
+            ext.recorded.push(result.clone());
+            result
+        }
+        None => f(),
+    };
+    ee.tr.set_extension(ext);
+    result
+}
+
+/// Compile call to TOTP.
+fn c_totp(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Binary, DataKind::Int]);
+    let secret = c_value(b, &mut args[0]);
+    let step = c_int(b, &mut args[1]);
+    Box::new(Totp { secret, step })
+}
+
+/// Compiled call to TOTP.
+struct Totp {
+    secret: CExpPtr<Value>,
+    step: CExpPtr<i64>,
+}
+impl CExp<i64> for Totp {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let secret = self.secret.eval(ee, d).bin();
+        let step = self.step.eval(ee, d) as u64;
+        totp(&secret, step)
+    }
+}
+
+/// Time-based one time password ( RFC 6238, HMAC-SHA1, 6 digits ) for the specified time step.
+fn totp(secret: &[u8], step: u64) -> i64 {
+    use hmac::{Hmac, Mac};
+    let mut mac = Hmac::<sha1::Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let h = mac.finalize().into_bytes();
+    let off = (h[19] & 15) as usize;
+    let code = u32::from_be_bytes([h[off] & 0x7f, h[off + 1], h[off + 2], h[off + 3]]);
+    (code % 1000000) as i64
+}
+
+/// Compile call to TOTPKEY.
+fn c_totp_key(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::Binary]);
+    let bytes = c_value(b, &mut args[0]);
+    Box::new(TotpKey { bytes })
+}
+
+/// Compiled call to TOTPKEY ( base32 encoding of secret, as used by authenticator apps ).
+struct TotpKey {
+    bytes: CExpPtr<Value>,
+}
+impl CExp<Value> for TotpKey {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+        let bytes = self.bytes.eval(ee, d).bin();
+        let mut result = String::new();
+        let (mut acc, mut bits) = (0u32, 0);
+        for b in bytes.iter() {
+            acc = (acc << 8) | *b as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                result.push(ALPHABET[(acc >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            result.push(ALPHABET[(acc << (5 - bits)) as usize & 31] as char);
+        }
+        Value::String(Rc::new(result))
+    }
+}
 
-    let _result = sender.send(&email)?;
-    Ok(())
+/// Compile call to QRSVG.
+fn c_qr_svg(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let s = c_value(b, &mut args[0]);
+    Box::new(QrSvg { s })
 }
 
-/// Update the database to reflect an email was sent.
-async fn email_sent(state: &SharedState, msg: u64) {
-    let mut st = ServerTrans::new();
-    st.x.qy.sql = Arc::new(format!("EXEC email.Sent({})", msg));
-    state.process(st).await;
+/// Compiled call to QRSVG.
+struct QrSvg {
+    s: CExpPtr<Value>,
+}
+impl CExp<Value> for QrSvg {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        use qrcode::{render::svg, QrCode};
+        let s = self.s.eval(ee, d).str();
+        let result = match QrCode::new(s.as_bytes()) {
+            Ok(code) => code.render::<svg::Color>().min_dimensions(200, 200).build(),
+            Err(e) => panic!("QRSVG error {e}"),
+        };
+        Value::String(Rc::new(result))
+    }
 }
 
-/// Update the database to reflect an error occurred sending an email.
-async fn email_error(state: &SharedState, msg: u64, retry: i8, err: String) {
-    let mut st = ServerTrans::new();
-    let src = format!("EXEC email.LogSendError({},{},'{}')", msg, retry, err);
-    st.x.qy.sql = Arc::new(src);
-    state.process(st).await;
+/// Compile call to RENDER.
+fn c_render(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(
+        b,
+        args,
+        &[DataKind::String, DataKind::String, DataKind::Bool],
+    );
+    let template = c_value(b, &mut args[0]);
+    let params = c_value(b, &mut args[1]);
+    let escape = c_bool(b, &mut args[2]);
+    Box::new(Render {
+        template,
+        params,
+        escape,
+    })
 }
 
-/////////////////////////////////////////////
-// Helper functions for building ServerTrans.
+/// Compiled call to RENDER ( substitute parameters into a template, see render ).
+struct Render {
+    template: CExpPtr<Value>,
+    params: CExpPtr<Value>,
+    escape: CExpPtr<bool>,
+}
+impl CExp<Value> for Render {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let template = self.template.eval(ee, d).str();
+        let params = self.params.eval(ee, d).str();
+        let escape = self.escape.eval(ee, d);
+        Value::String(Rc::new(render(&template, &params, escape)))
+    }
+}
 
-/// Get BTreeMap of cookies from Cookies.
-fn map_cookies(cookies: Cookies) -> BTreeMap<String, String> {
-    let mut result = BTreeMap::new();
-    for cookie in cookies.list() {
-        let (name, value) = cookie.name_value();
-        result.insert(name.to_string(), value.to_string());
+/// Substitute parameters into a template. {{name}} is replaced by the parameter value ( HTML-escaped if escape is true ),
+/// {{{name}}} is replaced by the value without escaping, an unknown name is replaced by nothing.
+/// The parameters are name=value pairs separated by '&', with the values encoded by web.UrlEncode.
+fn render(template: &str, params: &str, escape: bool) -> String {
+    let params: BTreeMap<&str, String> = params
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(n, v)| (n.trim(), url_decode(v)))
+        .collect();
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let (open, close) = if rest[start..].starts_with("{{{") {
+            (3, "}}}")
+        } else {
+            (2, "}}")
+        };
+        let Some(len) = rest[start + open..].find(close) else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let name = rest[start + open..start + open + len].trim();
+        let value = params.get(name).map_or("", |v| v.as_str());
+        if escape && open == 2 {
+            result.push_str(&html_escape(value));
+        } else {
+            result.push_str(value);
+        }
+        rest = &rest[start + open + len + close.len()..];
     }
+    result.push_str(rest);
     result
 }
 
-/// Get Vec of Parts from MultiPart.
-async fn map_parts(mp: Option<Multipart>) -> Vec<Part> {
-    let mut result = Vec::new();
-    if let Some(mut mp) = mp {
-        while let Some(field) = mp.next_field().await.unwrap() {
-            let name = field.name().unwrap().to_string();
-            let file_name = match field.file_name() {
-                Some(s) => s.to_string(),
-                None => "".to_string(),
-            };
-            let content_type = match field.content_type() {
-                Some(s) => s.to_string(),
-                None => "".to_string(),
-            };
-            let mut data = Vec::new();
-            let mut text = "".to_string();
-            if content_type.is_empty() {
-                if let Ok(s) = field.text().await {
-                    text = s;
-                }
-            } else if let Ok(bytes) = field.bytes().await {
-                data = bytes.to_vec()
+/// Decode %xx escapes ( as produced by web.UrlEncode ).
+fn url_decode(s: &str) -> String {
+    let b = s.as_bytes();
+    let mut result = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let hex = std::str::from_utf8(&b[i + 1..b.len().min(i + 3)]).unwrap_or_default();
+        match u8::from_str_radix(hex, 16) {
+            Ok(x) if b[i] == b'%' && hex.len() == 2 => {
+                result.push(x);
+                i += 3;
+            }
+            _ => {
+                result.push(b[i]);
+                i += 1;
             }
-            let mut part = Part::default();
-            part.name = name;
-            part.file_name = file_name;
-            part.content_type = content_type;
-            part.data = Arc::new(data);
-            part.text = text;
-            result.push(part);
         }
     }
-    result
+    String::from_utf8_lossy(&result).into_owned()
 }
 
-/////////////////////////////
-
-use argon2rs::argon2i_simple;
+/// Escape text for inclusion in HTML ( including attribute values ).
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
 
-/// Compile call to ARGON.
-fn c_argon(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+/// Compile call to SQLVERBS.
+fn c_sql_verbs(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
     check_types(b, args, &[DataKind::String, DataKind::String]);
-    let password = c_value(b, &mut args[0]);
-    let salt = c_value(b, &mut args[1]);
-    Box::new(Argon { password, salt })
+    let sql = c_value(b, &mut args[0]);
+    let allowed = c_value(b, &mut args[1]);
+    Box::new(SqlVerbs { sql, allowed })
 }
 
-/// Compiled call to ARGON.
-struct Argon {
-    password: CExpPtr<Value>,
-    salt: CExpPtr<Value>,
+/// Compiled call to SQLVERBS.
+struct SqlVerbs {
+    sql: CExpPtr<Value>,
+    allowed: CExpPtr<Value>,
 }
-impl CExp<Value> for Argon {
+impl CExp<Value> for SqlVerbs {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
-        let pw = self.password.eval(ee, d).str();
-        let salt = self.salt.eval(ee, d).str();
+        let sql = self.sql.eval(ee, d).str();
+        let allowed = self.allowed.eval(ee, d).str();
+        Value::String(Rc::new(sql_verbs(&sql, &allowed)))
+    }
+}
 
-        let result = argon2i_simple(&pw, &salt).to_vec();
-        Value::RcBinary(Rc::new(result))
+/// Space separated list of statement types ( SELECT, DROP etc. ) used in sql, excluding those in allowed.
+/// String literals are also checked ( they may be run by EXECUTE ), and EXECUTE is never excluded,
+/// as the SQL it runs can be computed, so it cannot be checked.
+fn sql_verbs(sql: &str, allowed: &str) -> String {
+    let allowed: Vec<String> = allowed
+        .split_whitespace()
+        .map(|s| s.to_ascii_uppercase())
+        .filter(|s| s != "EXECUTE")
+        .collect();
+    let mut result: Vec<&str> = Vec::new();
+    add_sql_verbs(sql, &allowed, &mut result);
+    result.join(" ")
+}
+
+/// Add statement types used in sql ( and string literals in sql ) to result, see sql_verbs.
+fn add_sql_verbs(sql: &str, allowed: &[String], result: &mut Vec<&str>) {
+    const VERBS: [&str; 11] = [
+        "SELECT", "INSERT", "UPDATE", "DELETE", "CREATE", "ALTER", "DROP", "RENAME", "EXEC",
+        "EXECUTE", "CHECK",
+    ];
+    let b = sql.as_bytes();
+    let skip_to = |i: usize, end: &str| match sql[i..].find(end) {
+        Some(x) => i + x + end.len(),
+        None => b.len(),
+    };
+    let mut i = 0;
+    while i < b.len() {
+        match b[i] {
+            b'\'' => {
+                // A quote in a literal is written as two quotes.
+                let start = i + 1;
+                i = skip_to(start, "'");
+                while b.get(i) == Some(&b'\'') {
+                    i = skip_to(i + 1, "'");
+                }
+                let end = if i > start && b[i - 1] == b'\'' {
+                    i - 1
+                } else {
+                    i
+                };
+                add_sql_verbs(&sql[start..end].replace("''", "'"), allowed, result);
+            }
+            b'[' => i = skip_to(i + 1, "]"),
+            b'-' if b.get(i + 1) == Some(&b'-') => i = skip_to(i, "\n"),
+            b'/' if b.get(i + 1) == Some(&b'*') => i = skip_to(i, "*/"),
+            c if c.is_ascii_alphabetic() => {
+                let start = i;
+                while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') {
+                    i += 1;
+                }
+                let word = sql[start..i].to_ascii_uppercase();
+                if let Some(verb) = VERBS.iter().find(|v| **v == word) {
+                    if !allowed.contains(&word) && !result.contains(verb) {
+                        result.push(verb);
+                    }
+                }
+            }
+            _ => i += 1,
+        }
     }
 }
 
-/// Compile call to SLEEP.
-fn c_sleep(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+/// Compile call to ROWLIMIT.
+fn c_row_limit(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
     check_types(b, args, &[DataKind::Int]);
-    let to = c_int(b, &mut args[0]);
-    Box::new(Sleep { to })
+    let n = c_int(b, &mut args[0]);
+    Box::new(RowLimit { n })
 }
 
-/// Compiled call to SLEEP
-struct Sleep {
-    to: CExpPtr<i64>,
+/// Compiled call to ROWLIMIT. Sets limit on rows selected, returns rows selected since limit was last set.
+struct RowLimit {
+    n: CExpPtr<i64>,
 }
-impl CExp<i64> for Sleep {
+impl CExp<i64> for RowLimit {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
-        let to = self.to.eval(ee, d);
+        let n = self.n.eval(ee, d);
+        let mut result = 0;
         let mut ext = ee.tr.get_extension();
-        if let Some(mut ext) = ext.downcast_mut::<TransExt>() {
-            ext.sleep = if to <= 0 { 1 } else { to as u64 };
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            result = ext.rows as i64;
+            ext.rows = 0;
+            ext.row_limit = if n > 0 { n as u64 } else { 0 };
         }
         ee.tr.set_extension(ext);
-        0
+        result
     }
 }
 
-/// Compile call to EMAILTX.
-fn c_email_tx(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
-    check_types(b, args, &[]);
-    Box::new(EmailTx {})
+/// Compile call to SQLHISTORY.
+fn c_sql_history(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[
+            DataKind::Int,
+            DataKind::String,
+            DataKind::Bool,
+            DataKind::String,
+            DataKind::Int,
+        ],
+    );
+    let user = c_int(b, &mut args[0]);
+    let sql = c_value(b, &mut args[1]);
+    let readonly = c_bool(b, &mut args[2]);
+    let error = c_value(b, &mut args[3]);
+    let rows = c_int(b, &mut args[4]);
+    Box::new(SqlHistoryCall {
+        user,
+        sql,
+        readonly,
+        error,
+        rows,
+    })
 }
 
-/// Compiled call to EMAILTX
-struct EmailTx {}
-impl CExp<i64> for EmailTx {
-    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+/// Compiled call to SQLHISTORY( user, sql, readonly, error, rows ).
+/// After the transaction ( even if it is read-only ), the last call is recorded in audit.SqlHistory.
+/// It should be called before running console SQL ( so the console SQL cannot record replica status ) and again after.
+struct SqlHistoryCall {
+    user: CExpPtr<i64>,
+    sql: CExpPtr<Value>,
+    readonly: CExpPtr<bool>,
+    error: CExpPtr<Value>,
+    rows: CExpPtr<i64>,
+}
+impl CExp<i64> for SqlHistoryCall {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let h = SqlHistory {
+            user: self.user.eval(ee, d),
+            time: ee.tr.global(0),
+            sql: self.sql.eval(ee, d).str().to_string(),
+            readonly: self.readonly.eval(ee, d),
+            error: self.error.eval(ee, d).str().to_string(),
+            rows: self.rows.eval(ee, d),
+        };
         let mut ext = ee.tr.get_extension();
-        if let Some(mut ext) = ext.downcast_mut::<TransExt>() {
-            ext.tx_email = true;
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.history = Some(h);
+            ext.console = true;
         }
         ee.tr.set_extension(ext);
         0
     }
 }
 
-/// Compile call to TRANSWAIT.
-fn c_trans_wait(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
-    check_types(b, args, &[]);
-    Box::new(TransWait {})
+/// Compile call to REPLICASEEN.
+fn c_replica_seen(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[
+            DataKind::String,
+            DataKind::String,
+            DataKind::Int,
+            DataKind::Bool,
+        ],
+    );
+    let name = c_value(b, &mut args[0]);
+    let ip = c_value(b, &mut args[1]);
+    let applied = c_int(b, &mut args[2]);
+    let reseed = c_bool(b, &mut args[3]);
+    Box::new(ReplicaSeen {
+        name,
+        ip,
+        applied,
+        reseed,
+    })
 }
 
-/// Compiled call to TRANSWAIT
-struct TransWait {}
-impl CExp<i64> for TransWait {
-    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
-        let mut ext = ee.tr.get_extension();
-        if let Some(mut ext) = ext.downcast_mut::<TransExt>() {
-            ext.trans_wait = true;
-        }
-        ee.tr.set_extension(ext);
+/// Compiled call to REPLICASEEN( name, ip, applied, reseed ).
+/// After the transaction ( even if it is read-only ), log.ReplicaSeen is called as a transaction that is not logged.
+struct ReplicaSeen {
+    name: CExpPtr<Value>,
+    ip: CExpPtr<Value>,
+    applied: CExpPtr<i64>,
+    reseed: CExpPtr<bool>,
+}
+impl CExp<i64> for ReplicaSeen {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let sql = format!(
+            "EXEC log.ReplicaSeen({},{},{},{})\n",
+            sql_quote(&self.name.eval(ee, d).str()),
+            sql_quote(&self.ip.eval(ee, d).str()),
+            self.applied.eval(ee, d),
+            self.reseed.eval(ee, d)
+        );
+        defer_replica_status(ee, &sql);
+        0
+    }
+}
+
+/// Compile call to REPLICAVERIFIED.
+fn c_replica_verified(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(
+        b,
+        args,
+        &[DataKind::String, DataKind::Int, DataKind::String],
+    );
+    let name = c_value(b, &mut args[0]);
+    let k = c_int(b, &mut args[1]);
+    let diverged = c_value(b, &mut args[2]);
+    Box::new(ReplicaVerified { name, k, diverged })
+}
+
+/// Compiled call to REPLICAVERIFIED( name, k, diverged ).
+/// After the transaction ( even if it is read-only ), log.ReplicaVerified is called as a transaction that is not logged.
+struct ReplicaVerified {
+    name: CExpPtr<Value>,
+    k: CExpPtr<i64>,
+    diverged: CExpPtr<Value>,
+}
+impl CExp<i64> for ReplicaVerified {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let sql = format!(
+            "EXEC log.ReplicaVerified({},{},{})\n",
+            sql_quote(&self.name.eval(ee, d).str()),
+            self.k.eval(ee, d),
+            sql_quote(&self.diverged.eval(ee, d).str())
+        );
+        defer_replica_status(ee, &sql);
         0
     }
 }
 
-/*
+/// Save SQL recording replica status, unless console SQL is being run ( see SQLHISTORY ).
+fn defer_replica_status(ee: &mut EvalEnv, sql: &str) {
+    let mut ext = ee.tr.get_extension();
+    if let Some(ext) = ext.downcast_mut::<TransExt>() {
+        if !ext.console {
+            ext.replica_status.push_str(sql);
+        }
+    }
+    ee.tr.set_extension(ext);
+}
+
 /// Compile call to BINPACK.
 fn c_binpack(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
     check_types(b, args, &[DataKind::Binary]);
@@ -767,13 +3237,10 @@ struct Binpack {
 }
 impl CExp<Value> for Binpack {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
-        if let Value::RcBinary(data) = self.bytes.eval(ee, d) {
-            let mut comp = flate3::Compressor::new();
-            let cb: Vec<u8> = comp.deflate(&data);
-            Value::RcBinary(Rc::new(cb))
-        } else {
-            panic!();
-        }
+        let data = self.bytes.eval(ee, d).bin();
+        let mut comp = flate3::Compressor::new();
+        let cb: Vec<u8> = comp.deflate(&data);
+        Value::RcBinary(Rc::new(cb))
     }
 }
 
@@ -790,15 +3257,169 @@ struct Binunpack {
 }
 impl CExp<Value> for Binunpack {
     fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
-        if let Value::RcBinary(data) = self.bytes.eval(ee, d) {
-            let ucb: Vec<u8> = flate3::inflate(&data);
-            Value::RcBinary(Rc::new(ucb))
-        } else {
-            panic!();
+        let data = self.bytes.eval(ee, d).bin();
+        let ucb: Vec<u8> = flate3::inflate(&data);
+        Value::RcBinary(Rc::new(ucb))
+    }
+}
+
+/// Compile call to SNAPSHOT.
+fn c_snapshot(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Int, DataKind::Int]);
+    let id = c_int(b, &mut args[0]);
+    let start = c_int(b, &mut args[1]);
+    Box::new(Snapshot { id, start })
+}
+
+/// Compiled call to SNAPSHOT. Response is a chunk of the snapshot with specified id ( zero for a new snapshot ) starting at page start.
+struct Snapshot {
+    id: CExpPtr<i64>,
+    start: CExpPtr<i64>,
+}
+impl CExp<i64> for Snapshot {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let id = self.id.eval(ee, d) as u64;
+        let start = self.start.eval(ee, d) as u64;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.snapshot = Some((id, start));
+        }
+        ee.tr.set_extension(ext);
+        0
+    }
+}
+
+/// Compile call to READONLY.
+fn c_readonly(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(ReadOnly {})
+}
+
+/// Compiled call to READONLY. Result is 1 if the transaction is read-only ( updates are not saved ), otherwise 0.
+struct ReadOnly {}
+impl CExp<i64> for ReadOnly {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let ext = ee.tr.get_extension();
+        let result = match ext.downcast_ref::<TransExt>() {
+            Some(ext) => ext.readonly as i64,
+            None => 0,
+        };
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Compile call to PROMOTE.
+fn c_promote(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[]);
+    Box::new(Promote {})
+}
+
+/// Compiled call to PROMOTE. If the server is a replica, it is promoted to master once the transaction completes.
+/// Result is 1 if the server is a replica, otherwise 0.
+struct Promote {}
+impl CExp<i64> for Promote {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> i64 {
+        let mut ext = ee.tr.get_extension();
+        let mut result = 0;
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            if ext.replica {
+                ext.promote = true;
+                result = 1;
+            }
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Compile call to TABLECHECKSUM.
+fn c_table_checksum(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String, DataKind::String]);
+    let schema = c_value(b, &mut args[0]);
+    let name = c_value(b, &mut args[1]);
+    Box::new(TableChecksum { schema, name })
+}
+
+/// Compiled call to TABLECHECKSUM. Result is the row count and hash of the specified table ( see table_checksum ).
+struct TableChecksum {
+    schema: CExpPtr<Value>,
+    name: CExpPtr<Value>,
+}
+impl CExp<Value> for TableChecksum {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let schema = self.schema.eval(ee, d).str();
+        let name = self.name.eval(ee, d).str();
+        Value::String(Rc::new(table_checksum(&ee.db, &schema, &name)))
+    }
+}
+
+/// Compile call to LOGRECORD.
+fn c_log_record(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[DataKind::String]);
+    let sql = c_value(b, &mut args[0]);
+    Box::new(LogRecord { sql })
+}
+
+/// Compiled call to LOGRECORD. Result is a log record ( see log_record ) for a transaction that runs sql.
+struct LogRecord {
+    sql: CExpPtr<Value>,
+}
+impl CExp<Value> for LogRecord {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> Value {
+        let mut qy = GenTransaction::new().qy;
+        qy.sql = Arc::new(self.sql.eval(ee, d).str().to_string());
+        Value::RcBinary(Rc::new(log_record(&qy, &[])))
+    }
+}
+
+/// Compile call to FRAMEADD.
+fn c_frame_add(b: &Block, args: &mut [Expr]) -> CExpPtr<i64> {
+    check_types(b, args, &[DataKind::Binary]);
+    let bytes = c_value(b, &mut args[0]);
+    Box::new(FrameAdd { bytes })
+}
+
+/// Compiled call to FRAMEADD. Appends the length of the data ( 8 bytes, little-endian ) followed by the data to the frames
+/// for the transaction ( see FRAMES ). Result is the total length of the frames.
+struct FrameAdd {
+    bytes: CExpPtr<Value>,
+}
+impl CExp<i64> for FrameAdd {
+    fn eval(&self, ee: &mut EvalEnv, d: &[u8]) -> i64 {
+        let data = self.bytes.eval(ee, d).bin();
+        let mut result = 0;
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            ext.frames
+                .extend_from_slice(&(data.len() as u64).to_le_bytes());
+            ext.frames.extend_from_slice(&data);
+            result = ext.frames.len() as i64;
+        }
+        ee.tr.set_extension(ext);
+        result
+    }
+}
+
+/// Compile call to FRAMES.
+fn c_frames(b: &Block, args: &mut [Expr]) -> CExpPtr<Value> {
+    check_types(b, args, &[]);
+    Box::new(Frames {})
+}
+
+/// Compiled call to FRAMES. Result is the frames added by FRAMEADD, which are then cleared.
+struct Frames {}
+impl CExp<Value> for Frames {
+    fn eval(&self, ee: &mut EvalEnv, _d: &[u8]) -> Value {
+        let mut result = Vec::new();
+        let mut ext = ee.tr.get_extension();
+        if let Some(ext) = ext.downcast_mut::<TransExt>() {
+            result = std::mem::take(&mut ext.frames);
         }
+        ee.tr.set_extension(ext);
+        Value::RcBinary(Rc::new(result))
     }
 }
-*/
 
 use clap::Parser;
 
@@ -821,10 +3442,26 @@ struct Args {
     #[clap(short, long, value_parser, default_value = "")]
     login: String,
 
+    /// File containing API key for replication ( default is RUSTWEB_API_KEY environment variable )
+    #[clap(short, long, value_parser, default_value = "")]
+    keyfile: String,
+
+    /// Name of replica reported to master ( default is the port )
+    #[clap(short, long, value_parser, default_value = "")]
+    name: String,
+
     /// Memory limit for page cache (in MB)
     #[clap(short, long, value_parser, default_value_t = 10)]
     mem: usize,
 
+    /// Interval in minutes for verifying a replica matches the master ( 0 for never )
+    #[clap(long, value_parser, default_value_t = 0)]
+    verify: u64,
+
+    /// Re-fetch tables that do not match the master when verifying a replica
+    #[clap(long, value_parser, default_value_t = false)]
+    repair: bool,
+
     /// Trace query time
     #[clap(long, value_parser, default_value_t = false)]
     tracetime: bool,
@@ -833,3 +3470,159 @@ struct Args {
     #[clap(long, value_parser, default_value_t = false)]
     tracemem: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_rfc6238() {
+        // Test vectors from RFC 6238 Appendix B ( SHA1 ), last 6 digits, time step 30 seconds.
+        let secret = b"12345678901234567890";
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ] {
+            assert_eq!(totp(secret, time / 30), code, "time {time}");
+        }
+    }
+
+    fn query(sql: &str) -> GenQuery {
+        let mut tr = GenTransaction::new();
+        tr.qy.sql = Arc::new(sql.to_string());
+        tr.qy.path = "/Test".to_string();
+        tr.qy
+    }
+
+    #[test]
+    fn log_record_round_trip() {
+        let recorded = vec![vec![1, 2, 3], Vec::new()];
+        // The second query is long enough to be compressed.
+        for sql in ["SELECT 1".to_string(), "SELECT 'x' ".repeat(100)] {
+            let rec = log_record(&query(&sql), &recorded);
+            assert!(rec.starts_with(LOG_MAGIC));
+            assert_eq!(rec[4] & LOG_DEFLATE != 0, sql.len() > 256);
+            let (qy, rd) = read_log_record(&rec).unwrap();
+            assert_eq!(*qy.sql, sql);
+            assert_eq!(qy.path, "/Test");
+            assert_eq!(rd, recorded);
+        }
+    }
+
+    #[test]
+    fn log_record_truncated() {
+        let rec = log_record(&query("SELECT 1"), &[vec![1, 2, 3]]);
+        for n in 0..rec.len() {
+            assert!(read_log_record(&rec[..n]).is_err(), "length {n}");
+        }
+        assert_eq!(
+            read_log_record(&rec[..9]).unwrap_err(),
+            "record is truncated"
+        );
+    }
+
+    #[test]
+    fn log_record_bad_crc() {
+        for sql in ["SELECT 1".to_string(), "SELECT 'x' ".repeat(100)] {
+            let mut rec = log_record(&query(&sql), &[]);
+            *rec.last_mut().unwrap() ^= 1;
+            assert_eq!(
+                read_log_record(&rec).unwrap_err(),
+                "record CRC check failed"
+            );
+        }
+    }
+
+    #[test]
+    fn log_record_version_mismatch() {
+        let rec = log_record(&query("SELECT 1"), &[]);
+
+        let mut bad = rec.clone();
+        bad[3] = LOG_FORMAT + 1;
+        assert!(read_log_record(&bad).unwrap_err().contains("not supported"));
+
+        // First digit of the rustweb version.
+        let mut bad = rec.clone();
+        bad[10] = b'9';
+        assert!(read_log_record(&bad).unwrap_err().contains("rustweb"));
+
+        // First digit of the rustdb version.
+        let mut bad = rec;
+        bad[11 + env!("CARGO_PKG_VERSION").len()] = b'9';
+        assert!(read_log_record(&bad).unwrap_err().contains("rustdb"));
+    }
+
+    #[test]
+    fn log_record_legacy() {
+        // Records written before LOG_MAGIC was introduced.
+        let ser = bincode::serialize(&query("SELECT 1")).unwrap();
+        let (qy, rd) = read_log_record(&ser).unwrap();
+        assert_eq!(*qy.sql, "SELECT 1");
+        assert!(rd.is_empty());
+        assert!(read_log_record(&ser[..ser.len() - 1]).is_err());
+
+        // Format 1 ( just the query ) and format 2 ( no rustdb version ) records.
+        let version = env!("CARGO_PKG_VERSION").as_bytes();
+        let recorded = vec![vec![7]];
+        let with_recorded = bincode::serialize(&(&query("SELECT 2"), &recorded)).unwrap();
+        for (format, payload) in [(1, &ser), (2, &with_recorded)] {
+            let mut rec = LOG_MAGIC.to_vec();
+            rec.push(format);
+            rec.push(0);
+            rec.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+            rec.push(version.len() as u8);
+            rec.extend_from_slice(version);
+            rec.extend_from_slice(payload);
+            let (qy, rd) = read_log_record(&rec).unwrap();
+            assert_eq!(qy.path, "/Test");
+            assert_eq!(rd.len(), format as usize - 1);
+        }
+    }
+
+    #[test]
+    fn sql_verbs_statements() {
+        assert_eq!(sql_verbs("select x from t", ""), "SELECT");
+        assert_eq!(sql_verbs("SELECT 1 DROP TABLE t", "SELECT"), "DROP");
+        assert_eq!(sql_verbs("Insert INTO t(x) VALUES (1)", "INSERT"), "");
+        assert_eq!(sql_verbs("SELECT Dropped FROM t", ""), "SELECT");
+    }
+
+    #[test]
+    fn sql_verbs_literals() {
+        assert_eq!(
+            sql_verbs("EXECUTE( 'DROP TABLE dbo.Order' )", ""),
+            "EXECUTE DROP"
+        );
+        assert_eq!(sql_verbs("SELECT 'it''s DELETE'", "SELECT DELETE"), "");
+        assert_eq!(sql_verbs("SELECT 'x'' DROP TABLE t --'", "SELECT"), "DROP");
+        // Nested literal, as in EXECUTE( 'EXECUTE( ''DROP TABLE t'' )' ).
+        assert_eq!(
+            sql_verbs("EXECUTE( 'SELECT ''DROP TABLE t''' )", "SELECT"),
+            "EXECUTE DROP"
+        );
+        // EXECUTE is reported even if it is in allowed.
+        assert_eq!(
+            sql_verbs("EXECUTE( 'SELECT 1' )", "SELECT EXECUTE"),
+            "EXECUTE"
+        );
+        assert_eq!(sql_verbs("SELECT 'unterminated DROP", "SELECT"), "DROP");
+    }
+
+    #[test]
+    fn sql_verbs_comments_and_names() {
+        assert_eq!(sql_verbs("SELECT 1 -- DROP TABLE t\n", "SELECT"), "");
+        assert_eq!(
+            sql_verbs("SELECT 1 /* DELETE */ UPDATE t SET x=1", "SELECT"),
+            "UPDATE"
+        );
+        assert_eq!(
+            sql_verbs("SELECT [Drop], [Delete] FROM [Update]", "SELECT"),
+            ""
+        );
+        assert_eq!(sql_verbs("SELECT 1 /* unterminated DROP", "SELECT"), "");
+    }
+}